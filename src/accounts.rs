@@ -1,46 +1,107 @@
 use crate::{
     error::EscrowError,
-    state::{EscrowAccount, MintAccount, TokenAccount},
+    pda::{find_config_address, find_deposit_address, find_escrow_address, token_account_address},
+    state::{
+        Config, ConfigAccount, DepositAccount, EscrowAccount, EscrowState, FeeSide, MintAccount,
+        NativeSide, TokenAccount, ESCROW_STATE_VERSION, MAX_FEE_BPS,
+    },
+    validation::{
+        check_config, check_deposit, check_escrow, check_escrow_any_version, check_harvest_mint,
+        check_mint, check_program_id, check_programs, check_signer, check_token_account,
+        check_token_account_owner, check_token_program, check_uninitialized,
+        check_upgrade_authority, check_writable,
+    },
 };
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
     program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_program,
+    sysvar::Sysvar,
 };
-use spl_associated_token_account::get_associated_token_address;
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+use spl_token::native_mint;
 
 pub struct InitEscrowAccount<'a> {
     pub initializer: &'a AccountInfo<'a>,
     pub escrow_state: &'a AccountInfo<'a>,
+    pub escrow_bump: u8,
     pub token_a_vault: &'a AccountInfo<'a>,
     pub token_a_founder: TokenAccount,
     pub token_a_mint: MintAccount,
     pub token_b_mint: MintAccount,
 }
 impl<'a> InitEscrowAccount<'a> {
-    pub fn unpack(accounts: &'a [AccountInfo<'a>]) -> Result<InitEscrowAccount<'a>, EscrowError> {
+    /// Position of the config account, checked for the pause switch before unpacking
+    pub const CONFIG_INDEX: usize = 9;
+
+    pub fn unpack(
+        accounts: &'a [AccountInfo<'a>],
+        seed: u64,
+        native_side: NativeSide,
+    ) -> Result<InitEscrowAccount<'a>, EscrowError> {
         let account_info_iter = &mut accounts.iter();
         let initializer = unwrap_iter(next_account_info(account_info_iter))?;
         let escrow_state = unwrap_iter(next_account_info(account_info_iter))?;
         let token_a_vault = unwrap_iter(next_account_info(account_info_iter))?;
-        let token_a_founder =
-            TokenAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
-        let token_a_mint = MintAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
-        let token_b_mint = MintAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
-        let _spl_token_program = unwrap_iter(next_account_info(account_info_iter))?;
-        let _ata_program = unwrap_iter(next_account_info(account_info_iter))?;
-        let _system_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let token_a_founder = unpack_token_account(
+            unwrap_iter(next_account_info(account_info_iter))?,
+            native_side.is_native_a(),
+            0,
+        )?;
+        let token_a_mint = unpack_mint(
+            unwrap_iter(next_account_info(account_info_iter))?,
+            native_side.is_native_a(),
+        )?;
+        let token_b_mint = unpack_mint(
+            unwrap_iter(next_account_info(account_info_iter))?,
+            native_side.is_native_b(),
+        )?;
+        let spl_token_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let ata_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let system_program = unwrap_iter(next_account_info(account_info_iter))?;
+        // the config is only read by the pause check of the processor
+        let _config = unwrap_iter(next_account_info(account_info_iter))?;
         // validate accounts
-        if !initializer.is_signer {
-            return Err(EscrowError::InvalidSigner);
+        check_signer(initializer)?;
+        check_writable(initializer.is_writable)?;
+        check_writable(escrow_state.is_writable)?;
+        check_writable(token_a_vault.is_writable)?;
+        check_writable(token_a_founder.is_writable)?;
+        if native_side.is_native_a() {
+            // native SOL is deposited straight from the initializer's wallet
+            if !token_a_founder.key.eq(initializer.key) {
+                return Err(EscrowError::NativeAccountMismatch);
+            }
+        } else {
+            check_token_account(&token_a_founder)?;
+            check_mint(&token_a_mint)?;
+            check_token_account_owner(
+                &token_a_founder,
+                initializer.key,
+                &token_a_mint.key,
+                EscrowError::TokenAFounderOwnerMismatch,
+                EscrowError::TokenAFounderMintMismatch,
+            )?;
         }
-        if !get_associated_token_address(escrow_state.key, &token_a_mint.key).eq(&token_a_vault.key)
-        {
-            return Err(EscrowError::VaultKeyMismatch);
-        };
+        if !native_side.is_native_b() {
+            check_mint(&token_b_mint)?;
+        }
+        check_token_program(spl_token_program, &token_a_mint)?;
+        check_programs(ata_program, system_program)?;
+        check_uninitialized(escrow_state)?;
+        let (escrow_key, escrow_bump) = find_escrow_address(initializer.key, seed);
+        if !escrow_key.eq(escrow_state.key) {
+            return Err(EscrowError::EscrowKeyMismatch);
+        }
+        check_vault(escrow_state, token_a_vault.key, &token_a_mint)?;
         match account_info_iter.next() {
-            Some(_) => return Err(EscrowError::NotEnoughAccountKeys),
+            Some(_) => Err(EscrowError::TooMuchAccountKeys),
             None => Ok(InitEscrowAccount {
                 initializer,
+                escrow_bump,
                 token_a_vault,
                 token_a_founder,
                 escrow_state,
@@ -62,38 +123,93 @@ pub struct ExchangeAccount<'a> {
     pub token_b_founder: TokenAccount,
     pub token_a_mint: MintAccount,
     pub token_b_mint: MintAccount,
+    pub config: Option<Config>,
+    pub fee_receiver: &'a AccountInfo<'a>,
+    pub fee_recipient: &'a AccountInfo<'a>,
 }
 impl<'a> ExchangeAccount<'a> {
+    /// Position of the config account, checked for the pause switch before unpacking
+    pub const CONFIG_INDEX: usize = 13;
+
     pub fn unpack(
         accounts: &'a [AccountInfo<'a>],
         amount_expected_by_taker: u64,
+        fill_amount: u64,
+        now: i64,
     ) -> Result<ExchangeAccount<'a>, EscrowError> {
         let account_info_iter = &mut accounts.iter();
         let taker = unwrap_iter(next_account_info(account_info_iter))?;
         let initializer = unwrap_iter(next_account_info(account_info_iter))?;
         let escrow_state = unwrap_iter(next_account_info(account_info_iter))?;
         let escrow_account_info = EscrowAccount::unpack(escrow_state)?;
-        let token_a_vault =
-            TokenAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
+        let native_side = escrow_account_info.info.native_side;
+        let token_a_vault = unpack_vault(
+            unwrap_iter(next_account_info(account_info_iter))?,
+            native_side.is_native_a(),
+        )?;
         let token_a_receiver = unwrap_iter(next_account_info(account_info_iter))?;
         let token_b_receiver = unwrap_iter(next_account_info(account_info_iter))?;
-        let token_b_founder =
-            TokenAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
+        let token_b_founder = unpack_token_account(
+            unwrap_iter(next_account_info(account_info_iter))?,
+            native_side.is_native_b(),
+            0,
+        )?;
 
-        let token_a_mint = MintAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
-        let token_b_mint = MintAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
-        let _spl_token_program = unwrap_iter(next_account_info(account_info_iter))?;
-        let _ata_program = unwrap_iter(next_account_info(account_info_iter))?;
-        let _system_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let token_a_mint = unpack_mint(
+            unwrap_iter(next_account_info(account_info_iter))?,
+            native_side.is_native_a(),
+        )?;
+        let token_b_mint = unpack_mint(
+            unwrap_iter(next_account_info(account_info_iter))?,
+            native_side.is_native_b(),
+        )?;
+        let token_a_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let token_b_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let ata_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let system_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let config = unwrap_iter(next_account_info(account_info_iter))?;
+        let fee_receiver = unwrap_iter(next_account_info(account_info_iter))?;
+        let fee_recipient = unwrap_iter(next_account_info(account_info_iter))?;
 
         // validate accounts
-        if !taker.is_signer {
-            return Err(EscrowError::InvalidSigner);
+        check_signer(taker)?;
+        check_writable(taker.is_writable)?;
+        check_writable(initializer.is_writable)?;
+        check_writable(escrow_account_info.is_writable)?;
+        check_writable(token_a_vault.is_writable)?;
+        check_writable(token_a_receiver.is_writable)?;
+        check_writable(token_b_receiver.is_writable)?;
+        check_writable(token_b_founder.is_writable)?;
+        check_escrow(&escrow_account_info)?;
+        if native_side.is_native_a() {
+            if !token_a_receiver.key.eq(taker.key) {
+                return Err(EscrowError::NativeAccountMismatch);
+            }
+        } else {
+            check_token_account(&token_a_vault)?;
+            check_mint(&token_a_mint)?;
+            check_harvest_mint(&token_a_mint)?;
+        }
+        if native_side.is_native_b() {
+            // native SOL is paid from the taker's wallet straight into the initializer's wallet
+            if !token_b_founder.key.eq(taker.key) {
+                return Err(EscrowError::NativeAccountMismatch);
+            }
+        } else {
+            check_token_account(&token_b_founder)?;
+            check_mint(&token_b_mint)?;
         }
-        if !get_associated_token_address(escrow_state.key, &token_a_mint.key).eq(&token_a_vault.key)
+        check_token_program(token_a_program, &token_a_mint)?;
+        check_token_program(token_b_program, &token_b_mint)?;
+        check_programs(ata_program, system_program)?;
+        // the escrow signs with the initializer stored in its state
+        if !initializer
+            .key
+            .eq(&escrow_account_info.info.initializer_pubkey)
         {
-            return Err(EscrowError::VaultKeyMismatch);
-        };
+            return Err(EscrowError::InitializerMismatch);
+        }
+        check_vault(escrow_state, &token_a_vault.key, &token_a_mint)?;
         if !amount_expected_by_taker.eq(&escrow_account_info.info.expected_amount) {
             return Err(EscrowError::ExpectedAmountMismatch);
         }
@@ -103,9 +219,44 @@ impl<'a> ExchangeAccount<'a> {
         if !token_b_mint.key.eq(&escrow_account_info.info.mint_b) {
             return Err(EscrowError::MintBMismatch);
         }
+        // token B is paid to the initializer recorded in the escrow, never where the taker chooses
+        if !token_account_address(
+            &escrow_account_info.info.initializer_pubkey,
+            &token_b_mint.key,
+            &token_b_mint.program_id,
+        )
+        .eq(token_b_receiver.key)
+        {
+            return Err(EscrowError::TokenBReceiverMismatch);
+        }
+        if !native_side.is_native_a() {
+            check_token_receiver(token_a_receiver, taker.key, &token_a_mint)?;
+        }
+        if !native_side.is_native_b() {
+            check_token_account_owner(
+                &token_b_founder,
+                taker.key,
+                &token_b_mint.key,
+                EscrowError::TokenBFounderOwnerMismatch,
+                EscrowError::TokenBFounderMintMismatch,
+            )?;
+        }
+        if escrow_account_info.info.is_expired(now) {
+            return Err(EscrowError::EscrowExpired);
+        }
+        if fill_amount == 0 || fill_amount > escrow_account_info.info.remaining_amount {
+            return Err(EscrowError::InvalidFillAmount);
+        }
+        let config = unpack_fee_config(
+            config,
+            fee_receiver,
+            fee_recipient,
+            &token_a_mint,
+            &token_b_mint,
+        )?;
 
         match account_info_iter.next() {
-            Some(_) => return Err(EscrowError::NotEnoughAccountKeys),
+            Some(_) => Err(EscrowError::TooMuchAccountKeys),
             None => Ok(ExchangeAccount {
                 taker,
                 initializer,
@@ -117,6 +268,721 @@ impl<'a> ExchangeAccount<'a> {
                 escrow_account_info,
                 token_a_mint,
                 token_b_mint,
+                config,
+                fee_receiver,
+                fee_recipient,
+            }),
+        }
+    }
+}
+
+pub struct CancelEscrowAccount<'a> {
+    pub initializer: &'a AccountInfo<'a>,
+    pub escrow_state: &'a AccountInfo<'a>,
+    pub escrow_account_info: EscrowAccount,
+    pub token_a_vault: TokenAccount,
+    pub token_a_receiver: &'a AccountInfo<'a>,
+    pub token_a_mint: MintAccount,
+}
+impl<'a> CancelEscrowAccount<'a> {
+    pub fn unpack(accounts: &'a [AccountInfo<'a>]) -> Result<CancelEscrowAccount<'a>, EscrowError> {
+        let account_info_iter = &mut accounts.iter();
+        let initializer = unwrap_iter(next_account_info(account_info_iter))?;
+        let escrow_state = unwrap_iter(next_account_info(account_info_iter))?;
+        let escrow_account_info = EscrowAccount::unpack(escrow_state)?;
+        let native_a = escrow_account_info.info.native_side.is_native_a();
+        let token_a_vault =
+            unpack_vault(unwrap_iter(next_account_info(account_info_iter))?, native_a)?;
+        let token_a_receiver = unwrap_iter(next_account_info(account_info_iter))?;
+        let token_a_mint =
+            unpack_mint(unwrap_iter(next_account_info(account_info_iter))?, native_a)?;
+        let spl_token_program = unwrap_iter(next_account_info(account_info_iter))?;
+
+        // validate accounts
+        check_signer(initializer)?;
+        check_writable(initializer.is_writable)?;
+        check_writable(escrow_account_info.is_writable)?;
+        check_writable(token_a_vault.is_writable)?;
+        check_writable(token_a_receiver.is_writable)?;
+        check_escrow(&escrow_account_info)?;
+        if native_a {
+            if !token_a_receiver.key.eq(initializer.key) {
+                return Err(EscrowError::NativeAccountMismatch);
+            }
+        } else {
+            check_token_account(&token_a_vault)?;
+            check_token_account(&TokenAccount::unpack(token_a_receiver)?)?;
+            check_mint(&token_a_mint)?;
+            check_harvest_mint(&token_a_mint)?;
+        }
+        check_token_program(spl_token_program, &token_a_mint)?;
+        if !initializer
+            .key
+            .eq(&escrow_account_info.info.initializer_pubkey)
+        {
+            return Err(EscrowError::InitializerMismatch);
+        }
+        check_vault(escrow_state, &token_a_vault.key, &token_a_mint)?;
+        if !token_a_mint.key.eq(&escrow_account_info.info.mint_a) {
+            return Err(EscrowError::MintAMismatch);
+        }
+
+        match account_info_iter.next() {
+            Some(_) => Err(EscrowError::TooMuchAccountKeys),
+            None => Ok(CancelEscrowAccount {
+                initializer,
+                escrow_state,
+                escrow_account_info,
+                token_a_vault,
+                token_a_receiver,
+                token_a_mint,
+            }),
+        }
+    }
+}
+
+pub struct ExpireEscrowAccount<'a> {
+    pub payer: &'a AccountInfo<'a>,
+    pub initializer: &'a AccountInfo<'a>,
+    pub escrow_state: &'a AccountInfo<'a>,
+    pub escrow_account_info: EscrowAccount,
+    pub token_a_vault: TokenAccount,
+    pub token_a_receiver: &'a AccountInfo<'a>,
+    pub token_a_mint: MintAccount,
+}
+impl<'a> ExpireEscrowAccount<'a> {
+    pub fn unpack(
+        accounts: &'a [AccountInfo<'a>],
+        now: i64,
+    ) -> Result<ExpireEscrowAccount<'a>, EscrowError> {
+        let account_info_iter = &mut accounts.iter();
+        let payer = unwrap_iter(next_account_info(account_info_iter))?;
+        let initializer = unwrap_iter(next_account_info(account_info_iter))?;
+        let escrow_state = unwrap_iter(next_account_info(account_info_iter))?;
+        let escrow_account_info = EscrowAccount::unpack(escrow_state)?;
+        let native_a = escrow_account_info.info.native_side.is_native_a();
+        let token_a_vault =
+            unpack_vault(unwrap_iter(next_account_info(account_info_iter))?, native_a)?;
+        let token_a_receiver = unwrap_iter(next_account_info(account_info_iter))?;
+        let token_a_mint =
+            unpack_mint(unwrap_iter(next_account_info(account_info_iter))?, native_a)?;
+        let spl_token_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let ata_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let system_program = unwrap_iter(next_account_info(account_info_iter))?;
+
+        // validate accounts
+        check_signer(payer)?;
+        check_writable(payer.is_writable)?;
+        check_writable(initializer.is_writable)?;
+        check_writable(escrow_account_info.is_writable)?;
+        check_writable(token_a_vault.is_writable)?;
+        check_writable(token_a_receiver.is_writable)?;
+        check_escrow(&escrow_account_info)?;
+        if !native_a {
+            check_token_account(&token_a_vault)?;
+            check_mint(&token_a_mint)?;
+            check_harvest_mint(&token_a_mint)?;
+        }
+        check_token_program(spl_token_program, &token_a_mint)?;
+        check_programs(ata_program, system_program)?;
+        if !initializer
+            .key
+            .eq(&escrow_account_info.info.initializer_pubkey)
+        {
+            return Err(EscrowError::InitializerMismatch);
+        }
+        check_vault(escrow_state, &token_a_vault.key, &token_a_mint)?;
+        let expected_receiver = if native_a {
+            *initializer.key
+        } else {
+            get_associated_token_address_with_program_id(
+                initializer.key,
+                &token_a_mint.key,
+                &token_a_mint.program_id,
+            )
+        };
+        if !expected_receiver.eq(token_a_receiver.key) {
+            return Err(EscrowError::TokenAReceiverMismatch);
+        };
+        if !token_a_mint.key.eq(&escrow_account_info.info.mint_a) {
+            return Err(EscrowError::MintAMismatch);
+        }
+        if !escrow_account_info.info.is_expired(now) {
+            return Err(EscrowError::EscrowNotExpired);
+        }
+
+        match account_info_iter.next() {
+            Some(_) => Err(EscrowError::TooMuchAccountKeys),
+            None => Ok(ExpireEscrowAccount {
+                payer,
+                initializer,
+                escrow_state,
+                escrow_account_info,
+                token_a_vault,
+                token_a_receiver,
+                token_a_mint,
+            }),
+        }
+    }
+}
+pub struct MigrateEscrowAccount<'a> {
+    pub initializer: &'a AccountInfo<'a>,
+    pub escrow_state: &'a AccountInfo<'a>,
+    pub escrow_account_info: EscrowAccount,
+    pub token_a_vault: TokenAccount,
+}
+impl<'a> MigrateEscrowAccount<'a> {
+    pub fn unpack(
+        accounts: &'a [AccountInfo<'a>],
+    ) -> Result<MigrateEscrowAccount<'a>, EscrowError> {
+        let account_info_iter = &mut accounts.iter();
+        let initializer = unwrap_iter(next_account_info(account_info_iter))?;
+        let escrow_state = unwrap_iter(next_account_info(account_info_iter))?;
+        let escrow_account_info = EscrowAccount::unpack(escrow_state)?;
+        let native_a = escrow_account_info.info.native_side.is_native_a();
+        let token_a_vault =
+            unpack_vault(unwrap_iter(next_account_info(account_info_iter))?, native_a)?;
+        let system_program = unwrap_iter(next_account_info(account_info_iter))?;
+
+        // validate accounts
+        check_signer(initializer)?;
+        check_writable(initializer.is_writable)?;
+        check_writable(escrow_account_info.is_writable)?;
+        check_escrow_any_version(&escrow_account_info)?;
+        if !native_a {
+            check_token_account(&token_a_vault)?;
+        }
+        // the vault is the ATA of the escrow under the token program owning it, the mint is not passed
+        if !token_account_address(
+            escrow_state.key,
+            &escrow_account_info.info.mint_a,
+            &token_a_vault.program_id,
+        )
+        .eq(&token_a_vault.key)
+        {
+            return Err(EscrowError::VaultKeyMismatch);
+        }
+        check_program_id(
+            system_program,
+            &system_program::ID,
+            EscrowError::InvalidSystemProgram,
+        )?;
+        if !initializer
+            .key
+            .eq(&escrow_account_info.info.initializer_pubkey)
+        {
+            return Err(EscrowError::InitializerMismatch);
+        }
+        if escrow_account_info.version == ESCROW_STATE_VERSION {
+            return Err(EscrowError::EscrowAlreadyMigrated);
+        }
+
+        match account_info_iter.next() {
+            Some(_) => Err(EscrowError::TooMuchAccountKeys),
+            None => Ok(MigrateEscrowAccount {
+                initializer,
+                escrow_state,
+                escrow_account_info,
+                token_a_vault,
+            }),
+        }
+    }
+}
+pub struct InitConfigAccount<'a> {
+    pub admin: &'a AccountInfo<'a>,
+    pub config: &'a AccountInfo<'a>,
+    pub config_bump: u8,
+    pub fee_recipient: &'a AccountInfo<'a>,
+}
+impl<'a> InitConfigAccount<'a> {
+    pub fn unpack(
+        accounts: &'a [AccountInfo<'a>],
+        fee_bps: u16,
+    ) -> Result<InitConfigAccount<'a>, EscrowError> {
+        let account_info_iter = &mut accounts.iter();
+        let admin = unwrap_iter(next_account_info(account_info_iter))?;
+        let config = unwrap_iter(next_account_info(account_info_iter))?;
+        let fee_recipient = unwrap_iter(next_account_info(account_info_iter))?;
+        let program_data = unwrap_iter(next_account_info(account_info_iter))?;
+        let system_program = unwrap_iter(next_account_info(account_info_iter))?;
+
+        // validate accounts
+        check_signer(admin)?;
+        check_writable(admin.is_writable)?;
+        check_writable(config.is_writable)?;
+        // only whoever can upgrade the program may choose its admin and fees
+        check_upgrade_authority(program_data, admin.key)?;
+        check_program_id(
+            system_program,
+            &system_program::ID,
+            EscrowError::InvalidSystemProgram,
+        )?;
+        if !config.data_is_empty() || !config.owner.eq(&system_program::ID) {
+            return Err(EscrowError::ConfigAlreadyInitialized);
+        }
+        let (config_key, config_bump) = find_config_address();
+        if !config_key.eq(config.key) {
+            return Err(EscrowError::ConfigKeyMismatch);
+        }
+        if fee_bps > MAX_FEE_BPS {
+            return Err(EscrowError::InvalidFeeBasisPoints);
+        }
+
+        match account_info_iter.next() {
+            Some(_) => Err(EscrowError::TooMuchAccountKeys),
+            None => Ok(InitConfigAccount {
+                admin,
+                config,
+                config_bump,
+                fee_recipient,
+            }),
+        }
+    }
+}
+
+pub struct UpdateConfigAccount<'a> {
+    pub admin: &'a AccountInfo<'a>,
+    pub config: &'a AccountInfo<'a>,
+    pub config_account_info: ConfigAccount,
+    pub fee_recipient: &'a AccountInfo<'a>,
+}
+impl<'a> UpdateConfigAccount<'a> {
+    pub fn unpack(
+        accounts: &'a [AccountInfo<'a>],
+        fee_bps: u16,
+    ) -> Result<UpdateConfigAccount<'a>, EscrowError> {
+        let account_info_iter = &mut accounts.iter();
+        let admin = unwrap_iter(next_account_info(account_info_iter))?;
+        let config = unwrap_iter(next_account_info(account_info_iter))?;
+        let config_account_info = ConfigAccount::unpack(config)?;
+        let fee_recipient = unwrap_iter(next_account_info(account_info_iter))?;
+
+        // validate accounts
+        check_signer(admin)?;
+        check_writable(config_account_info.is_writable)?;
+        check_config(&config_account_info)?;
+        if !admin.key.eq(&config_account_info.info.admin) {
+            return Err(EscrowError::AdminMismatch);
+        }
+        if fee_bps > MAX_FEE_BPS {
+            return Err(EscrowError::InvalidFeeBasisPoints);
+        }
+
+        match account_info_iter.next() {
+            Some(_) => Err(EscrowError::TooMuchAccountKeys),
+            None => Ok(UpdateConfigAccount {
+                admin,
+                config,
+                config_account_info,
+                fee_recipient,
+            }),
+        }
+    }
+}
+pub struct SetPausedAccount<'a> {
+    pub admin: &'a AccountInfo<'a>,
+    pub config: &'a AccountInfo<'a>,
+    pub config_account_info: ConfigAccount,
+}
+impl<'a> SetPausedAccount<'a> {
+    pub fn unpack(accounts: &'a [AccountInfo<'a>]) -> Result<SetPausedAccount<'a>, EscrowError> {
+        let account_info_iter = &mut accounts.iter();
+        let admin = unwrap_iter(next_account_info(account_info_iter))?;
+        let config = unwrap_iter(next_account_info(account_info_iter))?;
+        let config_account_info = ConfigAccount::unpack(config)?;
+
+        // validate accounts
+        check_signer(admin)?;
+        check_writable(config_account_info.is_writable)?;
+        check_config(&config_account_info)?;
+        if !admin.key.eq(&config_account_info.info.admin) {
+            return Err(EscrowError::AdminMismatch);
+        }
+
+        match account_info_iter.next() {
+            Some(_) => Err(EscrowError::TooMuchAccountKeys),
+            None => Ok(SetPausedAccount {
+                admin,
+                config,
+                config_account_info,
+            }),
+        }
+    }
+}
+
+pub struct TransferAdminAccount<'a> {
+    pub admin: &'a AccountInfo<'a>,
+    pub config: &'a AccountInfo<'a>,
+    pub config_account_info: ConfigAccount,
+    pub new_admin: &'a AccountInfo<'a>,
+}
+impl<'a> TransferAdminAccount<'a> {
+    pub fn unpack(
+        accounts: &'a [AccountInfo<'a>],
+    ) -> Result<TransferAdminAccount<'a>, EscrowError> {
+        let account_info_iter = &mut accounts.iter();
+        let admin = unwrap_iter(next_account_info(account_info_iter))?;
+        let config = unwrap_iter(next_account_info(account_info_iter))?;
+        let config_account_info = ConfigAccount::unpack(config)?;
+        let new_admin = unwrap_iter(next_account_info(account_info_iter))?;
+
+        // validate accounts
+        check_signer(admin)?;
+        check_writable(config_account_info.is_writable)?;
+        check_config(&config_account_info)?;
+        if !admin.key.eq(&config_account_info.info.admin) {
+            return Err(EscrowError::AdminMismatch);
+        }
+
+        match account_info_iter.next() {
+            Some(_) => Err(EscrowError::TooMuchAccountKeys),
+            None => Ok(TransferAdminAccount {
+                admin,
+                config,
+                config_account_info,
+                new_admin,
+            }),
+        }
+    }
+}
+
+pub struct AcceptAdminAccount<'a> {
+    pub new_admin: &'a AccountInfo<'a>,
+    pub config: &'a AccountInfo<'a>,
+    pub config_account_info: ConfigAccount,
+}
+impl<'a> AcceptAdminAccount<'a> {
+    pub fn unpack(accounts: &'a [AccountInfo<'a>]) -> Result<AcceptAdminAccount<'a>, EscrowError> {
+        let account_info_iter = &mut accounts.iter();
+        let new_admin = unwrap_iter(next_account_info(account_info_iter))?;
+        let config = unwrap_iter(next_account_info(account_info_iter))?;
+        let config_account_info = ConfigAccount::unpack(config)?;
+
+        // validate accounts
+        check_signer(new_admin)?;
+        check_writable(config_account_info.is_writable)?;
+        check_config(&config_account_info)?;
+        match config_account_info.info.pending_admin {
+            Some(pending_admin) if pending_admin.eq(new_admin.key) => {}
+            Some(_) => return Err(EscrowError::PendingAdminMismatch),
+            None => return Err(EscrowError::NoPendingAdmin),
+        }
+
+        match account_info_iter.next() {
+            Some(_) => Err(EscrowError::TooMuchAccountKeys),
+            None => Ok(AcceptAdminAccount {
+                new_admin,
+                config,
+                config_account_info,
+            }),
+        }
+    }
+}
+pub struct DepositEscrowAccount<'a> {
+    pub counterparty: &'a AccountInfo<'a>,
+    pub escrow_account_info: EscrowAccount,
+    pub deposit_state: &'a AccountInfo<'a>,
+    pub deposit_bump: u8,
+    pub token_b_vault: &'a AccountInfo<'a>,
+    pub token_b_founder: TokenAccount,
+    pub token_b_mint: MintAccount,
+}
+impl<'a> DepositEscrowAccount<'a> {
+    /// Position of the config account, checked for the pause switch before unpacking
+    pub const CONFIG_INDEX: usize = 9;
+
+    pub fn unpack(
+        accounts: &'a [AccountInfo<'a>],
+        amount_expected_by_counterparty: u64,
+        now: i64,
+    ) -> Result<DepositEscrowAccount<'a>, EscrowError> {
+        let account_info_iter = &mut accounts.iter();
+        let counterparty = unwrap_iter(next_account_info(account_info_iter))?;
+        let escrow_state = unwrap_iter(next_account_info(account_info_iter))?;
+        let escrow_account_info = EscrowAccount::unpack(escrow_state)?;
+        let deposit_state = unwrap_iter(next_account_info(account_info_iter))?;
+        let token_b_vault = unwrap_iter(next_account_info(account_info_iter))?;
+        let token_b_founder =
+            TokenAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
+        let token_b_mint = MintAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
+        let token_b_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let ata_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let system_program = unwrap_iter(next_account_info(account_info_iter))?;
+        // the config is only read by the pause check of the processor
+        let _config = unwrap_iter(next_account_info(account_info_iter))?;
+
+        // validate accounts
+        check_signer(counterparty)?;
+        check_writable(counterparty.is_writable)?;
+        check_writable(deposit_state.is_writable)?;
+        check_writable(token_b_vault.is_writable)?;
+        check_writable(token_b_founder.is_writable)?;
+        check_escrow(&escrow_account_info)?;
+        // native SOL vaults are the escrow accounts themselves, which settlement cannot close in one go
+        if escrow_account_info.info.native_side != NativeSide::None {
+            return Err(EscrowError::NativeDepositUnsupported);
+        }
+        check_token_account(&token_b_founder)?;
+        check_mint(&token_b_mint)?;
+        check_token_program(token_b_program, &token_b_mint)?;
+        check_programs(ata_program, system_program)?;
+        if !deposit_state.data_is_empty() || !deposit_state.owner.eq(&system_program::ID) {
+            return Err(EscrowError::DepositAlreadyInitialized);
+        }
+        let (deposit_key, deposit_bump) = find_deposit_address(&escrow_account_info.key);
+        if !deposit_key.eq(deposit_state.key) {
+            return Err(EscrowError::DepositKeyMismatch);
+        }
+        check_deposit_vault(deposit_state.key, token_b_vault.key, &token_b_mint)?;
+        if !amount_expected_by_counterparty.eq(&escrow_account_info.info.expected_amount) {
+            return Err(EscrowError::ExpectedAmountMismatch);
+        }
+        if !token_b_mint.key.eq(&escrow_account_info.info.mint_b) {
+            return Err(EscrowError::MintBMismatch);
+        }
+        check_token_account_owner(
+            &token_b_founder,
+            counterparty.key,
+            &token_b_mint.key,
+            EscrowError::TokenBFounderOwnerMismatch,
+            EscrowError::TokenBFounderMintMismatch,
+        )?;
+        if escrow_account_info.info.is_expired(now) {
+            return Err(EscrowError::EscrowExpired);
+        }
+
+        match account_info_iter.next() {
+            Some(_) => Err(EscrowError::TooMuchAccountKeys),
+            None => Ok(DepositEscrowAccount {
+                counterparty,
+                escrow_account_info,
+                deposit_state,
+                deposit_bump,
+                token_b_vault,
+                token_b_founder,
+                token_b_mint,
+            }),
+        }
+    }
+}
+
+pub struct SettleEscrowAccount<'a> {
+    pub settler: &'a AccountInfo<'a>,
+    pub initializer: &'a AccountInfo<'a>,
+    pub counterparty: &'a AccountInfo<'a>,
+    pub escrow_state: &'a AccountInfo<'a>,
+    pub escrow_account_info: EscrowAccount,
+    pub token_a_vault: TokenAccount,
+    pub deposit_state: &'a AccountInfo<'a>,
+    pub deposit_account_info: DepositAccount,
+    pub token_b_vault: TokenAccount,
+    pub token_a_receiver: &'a AccountInfo<'a>,
+    pub token_b_receiver: &'a AccountInfo<'a>,
+    pub token_a_mint: MintAccount,
+    pub token_b_mint: MintAccount,
+    pub config: Option<Config>,
+    pub fee_receiver: &'a AccountInfo<'a>,
+    pub fee_recipient: &'a AccountInfo<'a>,
+}
+impl<'a> SettleEscrowAccount<'a> {
+    /// Position of the config account, checked for the pause switch before unpacking
+    pub const CONFIG_INDEX: usize = 15;
+
+    pub fn unpack(
+        accounts: &'a [AccountInfo<'a>],
+        now: i64,
+    ) -> Result<SettleEscrowAccount<'a>, EscrowError> {
+        let account_info_iter = &mut accounts.iter();
+        let settler = unwrap_iter(next_account_info(account_info_iter))?;
+        let initializer = unwrap_iter(next_account_info(account_info_iter))?;
+        let counterparty = unwrap_iter(next_account_info(account_info_iter))?;
+        let escrow_state = unwrap_iter(next_account_info(account_info_iter))?;
+        let escrow_account_info = EscrowAccount::unpack(escrow_state)?;
+        let token_a_vault =
+            TokenAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
+        let deposit_state = unwrap_iter(next_account_info(account_info_iter))?;
+        let deposit_account_info = DepositAccount::unpack(deposit_state)?;
+        let token_b_vault =
+            TokenAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
+        let token_a_receiver = unwrap_iter(next_account_info(account_info_iter))?;
+        let token_b_receiver = unwrap_iter(next_account_info(account_info_iter))?;
+        let token_a_mint = MintAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
+        let token_b_mint = MintAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
+        let token_a_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let token_b_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let ata_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let system_program = unwrap_iter(next_account_info(account_info_iter))?;
+        let config = unwrap_iter(next_account_info(account_info_iter))?;
+        let fee_receiver = unwrap_iter(next_account_info(account_info_iter))?;
+        let fee_recipient = unwrap_iter(next_account_info(account_info_iter))?;
+
+        // validate accounts
+        check_signer(settler)?;
+        check_writable(settler.is_writable)?;
+        check_writable(initializer.is_writable)?;
+        check_writable(counterparty.is_writable)?;
+        check_writable(escrow_account_info.is_writable)?;
+        check_writable(token_a_vault.is_writable)?;
+        check_writable(deposit_account_info.is_writable)?;
+        check_writable(token_b_vault.is_writable)?;
+        check_writable(token_a_receiver.is_writable)?;
+        check_writable(token_b_receiver.is_writable)?;
+        check_harvest_mint(&token_a_mint)?;
+        check_harvest_mint(&token_b_mint)?;
+        check_escrow(&escrow_account_info)?;
+        check_deposit(&deposit_account_info)?;
+        check_token_account(&token_a_vault)?;
+        check_token_account(&token_b_vault)?;
+        check_mint(&token_a_mint)?;
+        check_mint(&token_b_mint)?;
+        check_token_program(token_a_program, &token_a_mint)?;
+        check_token_program(token_b_program, &token_b_mint)?;
+        check_programs(ata_program, system_program)?;
+        if !initializer
+            .key
+            .eq(&escrow_account_info.info.initializer_pubkey)
+        {
+            return Err(EscrowError::InitializerMismatch);
+        }
+        if !counterparty.key.eq(&deposit_account_info.info.counterparty) {
+            return Err(EscrowError::CounterpartyMismatch);
+        }
+        if !settler.key.eq(initializer.key) && !settler.key.eq(counterparty.key) {
+            return Err(EscrowError::UnauthorizedSettler);
+        }
+        if !deposit_account_info
+            .info
+            .escrow
+            .eq(&escrow_account_info.key)
+        {
+            return Err(EscrowError::DepositKeyMismatch);
+        }
+        check_vault(escrow_state, &token_a_vault.key, &token_a_mint)?;
+        check_deposit_vault(deposit_state.key, &token_b_vault.key, &token_b_mint)?;
+        if !token_a_mint.key.eq(&escrow_account_info.info.mint_a) {
+            return Err(EscrowError::MintAMismatch);
+        }
+        if !token_b_mint.key.eq(&escrow_account_info.info.mint_b) {
+            return Err(EscrowError::MintBMismatch);
+        }
+        // token B is paid to the initializer recorded in the escrow
+        if !token_account_address(
+            &escrow_account_info.info.initializer_pubkey,
+            &token_b_mint.key,
+            &token_b_mint.program_id,
+        )
+        .eq(token_b_receiver.key)
+        {
+            return Err(EscrowError::TokenBReceiverMismatch);
+        }
+        check_token_receiver(token_a_receiver, counterparty.key, &token_a_mint)?;
+        // a fill or a new escrow at the same address since the deposit changes what it pays for
+        let deposit = &deposit_account_info.info;
+        if !deposit.mint_a.eq(&escrow_account_info.info.mint_a)
+            || !deposit.mint_b.eq(&escrow_account_info.info.mint_b)
+            || deposit.amount_a != escrow_account_info.info.remaining_amount
+            || escrow_account_info
+                .info
+                .price_for_fill(deposit.amount_a)
+                .ne(&Some(deposit.amount_b))
+        {
+            return Err(EscrowError::DepositTermsMismatch);
+        }
+        if escrow_account_info.info.is_expired(now) {
+            return Err(EscrowError::EscrowExpired);
+        }
+        let config = unpack_fee_config(
+            config,
+            fee_receiver,
+            fee_recipient,
+            &token_a_mint,
+            &token_b_mint,
+        )?;
+
+        match account_info_iter.next() {
+            Some(_) => Err(EscrowError::TooMuchAccountKeys),
+            None => Ok(SettleEscrowAccount {
+                settler,
+                initializer,
+                counterparty,
+                escrow_state,
+                escrow_account_info,
+                token_a_vault,
+                deposit_state,
+                deposit_account_info,
+                token_b_vault,
+                token_a_receiver,
+                token_b_receiver,
+                token_a_mint,
+                token_b_mint,
+                config,
+                fee_receiver,
+                fee_recipient,
+            }),
+        }
+    }
+}
+
+pub struct WithdrawDepositAccount<'a> {
+    pub counterparty: &'a AccountInfo<'a>,
+    pub deposit_state: &'a AccountInfo<'a>,
+    pub deposit_account_info: DepositAccount,
+    pub token_b_vault: TokenAccount,
+    pub token_b_receiver: TokenAccount,
+    pub token_b_mint: MintAccount,
+}
+impl<'a> WithdrawDepositAccount<'a> {
+    pub fn unpack(
+        accounts: &'a [AccountInfo<'a>],
+    ) -> Result<WithdrawDepositAccount<'a>, EscrowError> {
+        let account_info_iter = &mut accounts.iter();
+        let counterparty = unwrap_iter(next_account_info(account_info_iter))?;
+        let deposit_state = unwrap_iter(next_account_info(account_info_iter))?;
+        let deposit_account_info = DepositAccount::unpack(deposit_state)?;
+        let token_b_vault =
+            TokenAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
+        let token_b_receiver =
+            TokenAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
+        let token_b_mint = MintAccount::unpack(unwrap_iter(next_account_info(account_info_iter))?)?;
+        let token_b_program = unwrap_iter(next_account_info(account_info_iter))?;
+
+        // validate accounts
+        check_signer(counterparty)?;
+        check_writable(counterparty.is_writable)?;
+        check_writable(deposit_account_info.is_writable)?;
+        check_writable(token_b_vault.is_writable)?;
+        check_writable(token_b_receiver.is_writable)?;
+        check_harvest_mint(&token_b_mint)?;
+        // the deposit does not depend on its escrow, it can be withdrawn once the escrow is gone
+        check_deposit(&deposit_account_info)?;
+        check_token_account(&token_b_vault)?;
+        check_token_account(&token_b_receiver)?;
+        check_mint(&token_b_mint)?;
+        check_token_program(token_b_program, &token_b_mint)?;
+        if !counterparty.key.eq(&deposit_account_info.info.counterparty) {
+            return Err(EscrowError::CounterpartyMismatch);
+        }
+        check_deposit_vault(deposit_state.key, &token_b_vault.key, &token_b_mint)?;
+        if !token_b_mint.key.eq(&deposit_account_info.info.mint_b) {
+            return Err(EscrowError::MintBMismatch);
+        }
+        check_token_account_owner(
+            &token_b_receiver,
+            counterparty.key,
+            &token_b_mint.key,
+            EscrowError::TokenBFounderOwnerMismatch,
+            EscrowError::TokenBFounderMintMismatch,
+        )?;
+
+        match account_info_iter.next() {
+            Some(_) => Err(EscrowError::TooMuchAccountKeys),
+            None => Ok(WithdrawDepositAccount {
+                counterparty,
+                deposit_state,
+                deposit_account_info,
+                token_b_vault,
+                token_b_receiver,
+                token_b_mint,
             }),
         }
     }
@@ -129,3 +995,135 @@ fn unwrap_iter<'a>(
         Err(_) => Err(EscrowError::NotEnoughAccountKeys),
     }
 }
+/// Unpacks the global config, which is `None` as long as it has not been initialized
+pub fn unpack_config(info: &AccountInfo) -> Result<Option<Config>, EscrowError> {
+    if info.data_is_empty() {
+        if !find_config_address().0.eq(info.key) {
+            return Err(EscrowError::ConfigKeyMismatch);
+        }
+        return Ok(None);
+    }
+    let config = ConfigAccount::unpack(info)?;
+    check_config(&config)?;
+    Ok(Some(config.info))
+}
+/// Unpacks the config of a trade and checks the fee recipient and the fee receiver, the ATA of
+/// the fee recipient for the mint of the fee side. No protocol fee is taken until the config is initialized
+fn unpack_fee_config(
+    config: &AccountInfo,
+    fee_receiver: &AccountInfo,
+    fee_recipient: &AccountInfo,
+    token_a_mint: &MintAccount,
+    token_b_mint: &MintAccount,
+) -> Result<Option<Config>, EscrowError> {
+    let config = unpack_config(config)?;
+    if let Some(config) = &config {
+        check_writable(fee_receiver.is_writable)?;
+        if !config.fee_recipient.eq(fee_recipient.key) {
+            return Err(EscrowError::FeeReceiverMismatch);
+        }
+        let fee_mint = match config.fee_side {
+            FeeSide::A => token_a_mint,
+            FeeSide::B => token_b_mint,
+        };
+        if !token_account_address(&config.fee_recipient, &fee_mint.key, &fee_mint.program_id)
+            .eq(fee_receiver.key)
+        {
+            return Err(EscrowError::FeeReceiverMismatch);
+        }
+    }
+    Ok(config)
+}
+/// A native SOL side passes the native mint as a marker instead of a mint account
+fn unpack_mint(info: &AccountInfo, native: bool) -> Result<MintAccount, EscrowError> {
+    if !native {
+        return MintAccount::unpack(info);
+    }
+    if !info.key.eq(&native_mint::ID) {
+        return Err(EscrowError::NativeMintMismatch);
+    }
+    Ok(MintAccount::native())
+}
+/// A native SOL side passes the wallet itself instead of a token account
+fn unpack_token_account(
+    info: &AccountInfo,
+    native: bool,
+    reserved: u64,
+) -> Result<TokenAccount, EscrowError> {
+    if !native {
+        return TokenAccount::unpack(info);
+    }
+    Ok(TokenAccount::native(info, reserved))
+}
+/// A native SOL vault is the escrow state, holding the deposit on top of its rent
+fn unpack_vault(info: &AccountInfo, native: bool) -> Result<TokenAccount, EscrowError> {
+    let reserved = match native {
+        true => Rent::get()
+            .map_err(|_| EscrowError::SysvarUnavailable)?
+            .minimum_balance(EscrowState::LEN),
+        false => 0,
+    };
+    unpack_token_account(info, native, reserved)
+}
+/// The token A receiver is either the taker's ATA, created by the exchange when missing,
+/// or any existing token account of the taker for mint A
+fn check_token_receiver(
+    token_a_receiver: &AccountInfo,
+    taker: &Pubkey,
+    token_a_mint: &MintAccount,
+) -> Result<(), EscrowError> {
+    if token_a_receiver.data_is_empty() {
+        if !token_account_address(taker, &token_a_mint.key, &token_a_mint.program_id)
+            .eq(token_a_receiver.key)
+        {
+            return Err(EscrowError::TokenAReceiverMismatch);
+        }
+        return Ok(());
+    }
+    let token_a_receiver = TokenAccount::unpack(token_a_receiver)?;
+    check_token_account(&token_a_receiver)?;
+    check_token_account_owner(
+        &token_a_receiver,
+        taker,
+        &token_a_mint.key,
+        EscrowError::TokenAReceiverOwnerMismatch,
+        EscrowError::TokenAReceiverMintMismatch,
+    )
+}
+/// The vault of a deposit is the token B ATA of the deposit state
+fn check_deposit_vault(
+    deposit_state: &Pubkey,
+    token_b_vault: &Pubkey,
+    token_b_mint: &MintAccount,
+) -> Result<(), EscrowError> {
+    if !get_associated_token_address_with_program_id(
+        deposit_state,
+        &token_b_mint.key,
+        &token_b_mint.program_id,
+    )
+    .eq(token_b_vault)
+    {
+        return Err(EscrowError::DepositVaultMismatch);
+    }
+    Ok(())
+}
+/// The vault is the ATA of the escrow state, or the escrow state itself for native SOL
+fn check_vault(
+    escrow_state: &AccountInfo,
+    token_a_vault: &Pubkey,
+    token_a_mint: &MintAccount,
+) -> Result<(), EscrowError> {
+    let expected_vault = if token_a_mint.is_native() {
+        *escrow_state.key
+    } else {
+        get_associated_token_address_with_program_id(
+            escrow_state.key,
+            &token_a_mint.key,
+            &token_a_mint.program_id,
+        )
+    };
+    if !expected_vault.eq(token_a_vault) {
+        return Err(EscrowError::VaultKeyMismatch);
+    }
+    Ok(())
+}
@@ -1,30 +1,177 @@
 use solana_program::msg;
 use solana_program::program_error::ProgramError;
-use strum_macros::AsRefStr;
-#[derive(Debug, Copy, Clone, AsRefStr)]
+use strum_macros::{AsRefStr, EnumDiscriminants};
+use thiserror::Error;
+#[derive(Debug, Clone, PartialEq, AsRefStr, EnumDiscriminants, Error)]
 pub enum EscrowError {
+    #[error("The expected amount does not match the escrow")]
     ExpectedAmountMismatch,
+    #[error("Unknown instruction")]
     InvalidInstructionType,
+    #[error("Malformed instruction data")]
     InvalidInstructionData,
+    #[error("Malformed escrow state")]
     InvalidEscrowState,
+    #[error("Invalid escrow vault")]
     InvalidEscrowVault,
+    #[error("A required signature is missing")]
     InvalidSigner,
+    #[error("Not enough accounts")]
     NotEnoughAccountKeys,
+    #[error("Too many accounts")]
     TooMuchAccountKeys,
+    #[error("Failed to deserialize a token account")]
     DeserializeTokenAccountError,
+    #[error("Failed to deserialize a mint")]
     DeserializeMintAccountError,
+    #[error("Failed to deserialize the escrow account")]
     DeserializeEscrowAccountError,
+    #[error("Mint A does not match the escrow")]
     MintAMismatch,
+    #[error("Mint B does not match the escrow")]
     MintBMismatch,
+    #[error("The vault is not the vault of the escrow")]
     VaultKeyMismatch,
+    #[error("The initializer does not match the escrow")]
+    InitializerMismatch,
+    #[error("The escrow has expired")]
+    EscrowExpired,
+    #[error("The escrow has not expired yet")]
+    EscrowNotExpired,
+    #[error("Invalid token A receiver")]
+    TokenAReceiverMismatch,
+    #[error("The amounts to trade and expected must not be zero")]
+    InvalidTradeAmount,
+    #[error("The fill amount must be positive and at most the remaining amount")]
+    InvalidFillAmount,
+    #[error("The fill is too small to be worth any token B")]
+    FillTooSmall,
+    #[error("The escrow is not at its program address")]
+    EscrowKeyMismatch,
+    #[error("An account that must be writable is not")]
+    AccountNotWritable,
+    #[error("The token program does not own the mint")]
+    InvalidTokenProgram,
+    #[error("Invalid associated token program")]
+    InvalidAssociatedTokenProgram,
+    #[error("Invalid system program")]
+    InvalidSystemProgram,
+    #[error("The token account is not owned by a token program")]
+    InvalidTokenAccountOwner,
+    #[error("The mint is not owned by a token program")]
+    InvalidMintOwner,
+    #[error("The escrow is not owned by this program")]
+    InvalidEscrowOwner,
+    #[error("The escrow is not initialized")]
+    EscrowNotInitialized,
+    #[error("The escrow is already initialized")]
+    EscrowAlreadyInitialized,
+    /// A cross-program invocation failed, carries the error returned by the callee
+    #[error("A cross-program invocation failed")]
+    CpiFailed(ProgramError),
+    #[error("Failed to borrow account data")]
+    AccountBorrowFailed,
+    #[error("Arithmetic overflow")]
+    ArithmeticOverflow,
+    #[error("A sysvar is unavailable")]
+    SysvarUnavailable,
+    #[error("The transfer fee of token B is too high")]
+    TransferFeeTooHigh,
+    #[error("A native SOL side must pass the native mint")]
+    NativeMintMismatch,
+    #[error("A native SOL side must pass the wallet itself")]
+    NativeAccountMismatch,
+    #[error("The escrow is reserved for another taker")]
+    UnauthorizedTaker,
+    #[error("The taker is not proven to be in the allowlist")]
+    InvalidTakerProof,
+    #[error("The taker would receive less token A than the minimum")]
+    AmountABelowMinimum,
+    #[error("The escrow must be migrated first")]
+    EscrowNeedsMigration,
+    #[error("The escrow already uses the current layout")]
+    EscrowAlreadyMigrated,
+    #[error("Failed to resize an account")]
+    ReallocFailed,
+    #[error("The account is of another type")]
+    InvalidAccountType,
+    #[error("Malformed config")]
+    InvalidConfigState,
+    #[error("The config is not owned by this program")]
+    InvalidConfigOwner,
+    #[error("The config is not at its program address")]
+    ConfigKeyMismatch,
+    #[error("The config is already initialized")]
+    ConfigAlreadyInitialized,
+    #[error("The signer is not the admin")]
+    AdminMismatch,
+    #[error("The fee must be at most 1000 basis points")]
+    InvalidFeeBasisPoints,
+    #[error("Invalid fee receiver")]
+    FeeReceiverMismatch,
+    #[error("The program is paused")]
+    ProgramPaused,
+    #[error("No admin handover is pending")]
+    NoPendingAdmin,
+    #[error("The signer is not the pending admin")]
+    PendingAdminMismatch,
+    #[error("The instruction data ends early")]
+    InstructionDataTooShort,
+    #[error("The instruction data has trailing bytes")]
+    InstructionDataTooLong,
+    #[error("The token B receiver is not the initializer's token B account")]
+    TokenBReceiverMismatch,
+    #[error("The token A account is not the initializer's")]
+    TokenAFounderOwnerMismatch,
+    #[error("The token A account does not hold mint A")]
+    TokenAFounderMintMismatch,
+    #[error("The token A receiver is not the taker's")]
+    TokenAReceiverOwnerMismatch,
+    #[error("The token A receiver does not hold mint A")]
+    TokenAReceiverMintMismatch,
+    #[error("The token B account is not the taker's")]
+    TokenBFounderOwnerMismatch,
+    #[error("The token B account does not hold mint B")]
+    TokenBFounderMintMismatch,
+    #[error("Native SOL escrows do not take deposits")]
+    NativeDepositUnsupported,
+    #[error("Malformed deposit state")]
+    InvalidDepositState,
+    #[error("The deposit is not owned by this program")]
+    InvalidDepositOwner,
+    #[error("The deposit is not initialized")]
+    DepositNotInitialized,
+    #[error("The deposit is not the deposit of the escrow")]
+    DepositKeyMismatch,
+    #[error("The escrow already holds a deposit")]
+    DepositAlreadyInitialized,
+    #[error("The vault is not the vault of the deposit")]
+    DepositVaultMismatch,
+    #[error("The counterparty does not match the deposit")]
+    CounterpartyMismatch,
+    #[error("Only the initializer or the counterparty can settle")]
+    UnauthorizedSettler,
+    #[error("The deposit no longer matches the terms of the escrow")]
+    DepositTermsMismatch,
+    #[error("The program data account is not this program's")]
+    InvalidProgramData,
+    #[error("The signer is not the upgrade authority of the program")]
+    UpgradeAuthorityMismatch,
 }
 
 impl From<EscrowError> for ProgramError {
     fn from(e: EscrowError) -> Self {
-        ProgramError::Custom(e as u32)
+        match e {
+            EscrowError::CpiFailed(e) => e,
+            e => ProgramError::Custom(e.code()),
+        }
     }
 }
 impl EscrowError {
+    /// The custom error code, the position of the variant in [EscrowError]
+    pub fn code(&self) -> u32 {
+        EscrowErrorDiscriminants::from(self) as u32
+    }
     pub fn print_into(self) -> ProgramError {
         msg!(format!("Error: {}", self.as_ref()).as_str());
         self.into()
@@ -0,0 +1,95 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{log::sol_log_data, pubkey::Pubkey};
+
+/// Events logged by the program through `sol_log_data`.
+///
+/// Each event is a single borsh-serialized [EscrowEvent], the runtime logs it base64-encoded
+/// as `Program data: <base64>`, the leading enum tag telling the event types apart.
+#[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub enum EscrowEvent {
+    Created(EscrowCreated),
+    Exchanged(EscrowExchanged),
+    Cancelled(EscrowCancelled),
+    Deposited(EscrowDeposited),
+    DepositWithdrawn(DepositWithdrawn),
+}
+
+/// An escrow was initialized and funded with token A
+#[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct EscrowCreated {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    /// amount of token A held by the vault after the deposit
+    pub deposit_amount: u64,
+    /// amount of token B asked for the whole deposit
+    pub expected_amount: u64,
+    pub seed: u64,
+    pub expiry: Option<i64>,
+}
+
+/// A taker filled the escrow, partly or fully
+#[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct EscrowExchanged {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub taker: Pubkey,
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    /// amount of token A sent to the taker
+    pub amount_a: u64,
+    /// amount of token B sent to the initializer
+    pub amount_b: u64,
+    /// protocol fee skimmed from the fee side
+    pub fee_amount: u64,
+    /// amount of token A left in the escrow, zero once it is closed
+    pub remaining_amount: u64,
+    pub seed: u64,
+}
+
+/// An escrow was refunded to its initializer and closed
+#[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct EscrowCancelled {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub mint_a: Pubkey,
+    /// amount of token A refunded
+    pub refund_amount: u64,
+    pub seed: u64,
+    /// true when refunded through `Expire` rather than `Cancel`
+    pub expired: bool,
+}
+
+/// A counterparty deposited token B against an escrow, settlement swaps it for `amount_a`
+#[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct EscrowDeposited {
+    pub escrow: Pubkey,
+    pub deposit: Pubkey,
+    pub counterparty: Pubkey,
+    pub mint_b: Pubkey,
+    /// amount of token A the deposit buys
+    pub amount_a: u64,
+    /// amount of token B paid for `amount_a`, the vault also holding the transfer fee of settling it
+    pub amount_b: u64,
+}
+
+/// A counterparty took its deposit back before settlement
+#[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct DepositWithdrawn {
+    pub escrow: Pubkey,
+    pub deposit: Pubkey,
+    pub counterparty: Pubkey,
+    pub mint_b: Pubkey,
+    /// amount of token B refunded
+    pub refund_amount: u64,
+}
+
+impl EscrowEvent {
+    /// Logs the event as program data
+    pub fn emit(&self) {
+        if let Ok(data) = borsh::to_vec(self) {
+            sol_log_data(&[&data]);
+        }
+    }
+}
@@ -1,24 +1,74 @@
-use solana_program::msg;
-use std::convert::TryInto;
-
-use crate::error::EscrowError;
+use crate::{
+    error::EscrowError,
+    pda::{
+        find_config_address_with_program_id, find_deposit_address_with_program_id,
+        find_escrow_address_with_program_id, find_program_data_address_with_program_id,
+        token_account_address,
+    },
+    state::{FeeSide, NativeSide},
+};
+use borsh::{BorshDeserialize, BorshSerialize};
+use shank::ShankInstruction;
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    msg,
+    pubkey::Pubkey,
+    system_program,
+};
+use std::io::Read;
 use strum_macros::AsRefStr;
-#[derive(AsRefStr)]
+/// Instructions are borsh-encoded, the leading tag byte being the position of the variant,
+/// so new instructions are only ever appended.
+#[derive(Clone, Debug, PartialEq, AsRefStr, BorshSerialize, BorshDeserialize, ShankInstruction)]
 pub enum EscrowInstruction {
     /// Starts the trade by creating and populating an escrow account and transferring token of the given mint(Mint A) to the ATA owned by Escrow account
     ///
-    ///
-    /// Accounts expected:
-    ///
-    /// 0. `[signer, writable]` The account of the person initializing the escrow
-    /// 3. `[writable]` The escrow account, it will hold all necessary info about the trade.
-    /// 1. `[writable]` Temporary token A account  owned by the escrow account
-    /// 2. `[writable]` The initializer's A token account for the token they will transfer
-    /// 3. `[writable]` The mint of token A.
-    /// 3. `[writable]` The mint of token B.
-    /// 5. `[]` The token program
-    /// 5. `[]` The associated token program
-    /// 5. `[]` The system program
+    /// For a native SOL side A the vault is the escrow account itself, the token A account is the initializer,
+    /// the mint of token A is the native mint and the token program is the system program.
+    /// For a native SOL side B the mint of token B is the native mint.
+    #[account(
+        0,
+        signer,
+        writable,
+        name = "initializer",
+        desc = "The account of the person initializing the escrow"
+    )]
+    #[account(
+        1,
+        writable,
+        name = "escrow_state",
+        desc = "The escrow account, it will hold all necessary info about the trade"
+    )]
+    #[account(
+        2,
+        writable,
+        name = "token_a_vault",
+        desc = "Temporary token A account owned by the escrow account"
+    )]
+    #[account(
+        3,
+        writable,
+        name = "token_a_founder",
+        desc = "The initializer's A token account for the token they will transfer"
+    )]
+    #[account(4, name = "mint_a", desc = "The mint of token A")]
+    #[account(5, name = "mint_b", desc = "The mint of token B")]
+    #[account(
+        6,
+        name = "token_a_program",
+        desc = "The token program owning mint A, either spl-token or token-2022"
+    )]
+    #[account(
+        7,
+        name = "associated_token_program",
+        desc = "The associated token program"
+    )]
+    #[account(8, name = "system_program", desc = "The system program")]
+    #[account(
+        9,
+        name = "config",
+        desc = "The config account, new escrows are refused while the program is paused"
+    )]
     InitEscrow {
         /// amount of A token to trade
         amount_to_trade: u64,
@@ -26,74 +76,1071 @@ pub enum EscrowInstruction {
         amount_expected: u64,
         /// random seed
         seed: u64,
+        /// optional unix timestamp after which the escrow can no longer be taken
+        expiry: Option<i64>,
+        /// which side of the trade, if any, is native SOL instead of a token
+        native_side: NativeSide,
+        /// optional taker the escrow is reserved for, anyone can take it when absent
+        allowed_taker: Option<Pubkey>,
+        /// optional merkle root of the keccak hashes of the takers allowed to take the escrow
+        taker_merkle_root: Option<[u8; 32]>,
     },
-    /// Accepts a trade
-    ///
-    ///
-    /// Accounts expected:
+    /// Accepts a trade, either the whole remaining amount of token A or a part of it.
+    /// The escrow is closed once nothing remains.
+    /// Transfer fees of token B are paid on top by the taker, transfer fees of token A are deducted from what they receive.
     ///
-    /// 0. `[signer]` The account of the person taking the trade
-    /// 1. `[writable]` The taker's token account for the token they send
-    /// 2. `[writable]` The taker's token account for the token they will receive should the trade go through
-    /// 3. `[writable]` The PDA's temp token account to get tokens from and eventually close
-    /// 4. `[writable]` The initializer's main account to send their rent fees to
-    /// 5. `[writable]` The initializer's token account that will receive tokens
-    /// 6. `[writable]` The escrow account holding the escrow info
-    /// 7. `[]` The token program
-    /// 8. `[]` The PDA account
+    /// For a native SOL side the token accounts are the wallets themselves (the escrow account for the vault),
+    /// the mint is the native mint and its token program is the system program.
+    #[account(
+        0,
+        signer,
+        writable,
+        name = "taker",
+        desc = "The account of the person taking the trade"
+    )]
+    #[account(
+        1,
+        writable,
+        name = "initializer",
+        desc = "The initializer's main account to send their rent fees to"
+    )]
+    #[account(
+        2,
+        writable,
+        name = "escrow_state",
+        desc = "The escrow account holding the escrow info"
+    )]
+    #[account(
+        3,
+        writable,
+        name = "token_a_vault",
+        desc = "The PDA's temp token account to get tokens from and eventually close"
+    )]
+    #[account(
+        4,
+        writable,
+        name = "token_a_receiver",
+        desc = "The taker's token account for the token they will receive should the trade go through"
+    )]
+    #[account(
+        5,
+        writable,
+        name = "token_b_receiver",
+        desc = "The token account that will receive token B"
+    )]
+    #[account(
+        6,
+        writable,
+        name = "token_b_founder",
+        desc = "The taker's token account for the token they send"
+    )]
+    #[account(
+        7,
+        name = "mint_a",
+        desc = "The mint of token A, writable when it charges a transfer fee, the fees withheld by the vault being harvested to it on close"
+    )]
+    #[account(8, name = "mint_b", desc = "The mint of token B")]
+    #[account(9, name = "token_a_program", desc = "The token program owning mint A")]
+    #[account(10, name = "token_b_program", desc = "The token program owning mint B")]
+    #[account(
+        11,
+        name = "associated_token_program",
+        desc = "The associated token program"
+    )]
+    #[account(12, name = "system_program", desc = "The system program")]
+    #[account(
+        13,
+        name = "config",
+        desc = "The config account, the protocol fee is skipped while it is not initialized and exchanges are refused while the program is paused"
+    )]
+    #[account(
+        14,
+        writable,
+        name = "fee_receiver",
+        desc = "The fee recipient's ATA for the mint of the fee side, created by the taker when missing; for a native fee side the recipient wallet itself, which must already hold the rent-exempt minimum"
+    )]
+    #[account(
+        15,
+        name = "fee_recipient",
+        desc = "The fee recipient wallet of the config, owning the fee receiver"
+    )]
     Exchange {
         /// the amount the taker expects to be paid in the other token, as a u64 because that's the max possible supply of a token
         amount: u64,
+        /// the amount of token A the taker buys, at most the remaining amount of the escrow
+        fill_amount: u64,
+        /// the minimum amount of token A the taker expects to receive, net of the protocol fee
+        /// and of the transfer fee of token A
+        min_amount_a: u64,
+        /// proof that the taker belongs to the allowlist of the escrow, empty for an open escrow
+        proof: Vec<[u8; 32]>,
+    },
+    /// Cancels a trade, refunding token A to the initializer and closing the escrow
+    #[account(
+        0,
+        signer,
+        writable,
+        name = "initializer",
+        desc = "The initializer of the escrow, receives the rent of the closed accounts"
+    )]
+    #[account(
+        1,
+        writable,
+        name = "escrow_state",
+        desc = "The escrow account holding the escrow info"
+    )]
+    #[account(
+        2,
+        writable,
+        name = "token_a_vault",
+        desc = "The vault ATA owned by the escrow account to refund and close"
+    )]
+    #[account(
+        3,
+        writable,
+        name = "token_a_receiver",
+        desc = "The initializer's token account that will receive token A back"
+    )]
+    #[account(
+        4,
+        name = "mint_a",
+        desc = "The mint of token A, writable when it charges a transfer fee, the fees withheld by the vault being harvested to it on close"
+    )]
+    #[account(5, name = "token_a_program", desc = "The token program owning mint A")]
+    Cancel,
+    /// Refunds an expired trade to the initializer and closes the escrow, callable by anyone
+    #[account(
+        0,
+        signer,
+        writable,
+        name = "payer",
+        desc = "The account triggering the refund, pays for the initializer's ATA if needed"
+    )]
+    #[account(
+        1,
+        writable,
+        name = "initializer",
+        desc = "The initializer of the escrow, receives the rent of the closed accounts"
+    )]
+    #[account(
+        2,
+        writable,
+        name = "escrow_state",
+        desc = "The escrow account holding the escrow info"
+    )]
+    #[account(
+        3,
+        writable,
+        name = "token_a_vault",
+        desc = "The vault ATA owned by the escrow account to refund and close"
+    )]
+    #[account(
+        4,
+        writable,
+        name = "token_a_receiver",
+        desc = "The initializer's token A ATA that will receive token A back"
+    )]
+    #[account(
+        5,
+        name = "mint_a",
+        desc = "The mint of token A, writable when it charges a transfer fee, the fees withheld by the vault being harvested to it on close"
+    )]
+    #[account(6, name = "token_a_program", desc = "The token program owning mint A")]
+    #[account(
+        7,
+        name = "associated_token_program",
+        desc = "The associated token program"
+    )]
+    #[account(8, name = "system_program", desc = "The system program")]
+    Expire,
+    /// Moves an escrow stored in the headerless v1 layout to the current versioned layout,
+    /// the initializer pays the rent of the extra space
+    #[account(
+        0,
+        signer,
+        writable,
+        name = "initializer",
+        desc = "The initializer of the escrow"
+    )]
+    #[account(
+        1,
+        writable,
+        name = "escrow_state",
+        desc = "The escrow account to migrate"
+    )]
+    #[account(
+        2,
+        name = "token_a_vault",
+        desc = "The vault of the escrow, whose balance becomes the deposit of a v1 escrow"
+    )]
+    #[account(3, name = "system_program", desc = "The system program")]
+    MigrateEscrow,
+    /// Creates the global config, the upgrade authority of the program becomes its admin
+    #[account(
+        0,
+        signer,
+        writable,
+        name = "admin",
+        desc = "The upgrade authority of the program, becomes the admin and pays for the config account"
+    )]
+    #[account(1, writable, name = "config", desc = "The config account")]
+    #[account(
+        2,
+        name = "fee_recipient",
+        desc = "The wallet receiving the protocol fees"
+    )]
+    #[account(
+        3,
+        name = "program_data",
+        desc = "The program data account of the program, recording its upgrade authority"
+    )]
+    #[account(4, name = "system_program", desc = "The system program")]
+    InitConfig {
+        /// protocol fee in basis points of the fee side of each exchange
+        fee_bps: u16,
+        /// which leg of the exchange the fee is taken from
+        fee_side: FeeSide,
+    },
+    /// Updates the protocol fee of the global config
+    #[account(0, signer, name = "admin", desc = "The admin of the config")]
+    #[account(1, writable, name = "config", desc = "The config account")]
+    #[account(
+        2,
+        name = "fee_recipient",
+        desc = "The wallet receiving the protocol fees"
+    )]
+    UpdateConfig {
+        /// protocol fee in basis points of the fee side of each exchange
+        fee_bps: u16,
+        /// which leg of the exchange the fee is taken from
+        fee_side: FeeSide,
+    },
+    /// Pauses or resumes new escrows and exchanges, refunds stay available while paused
+    #[account(0, signer, name = "admin", desc = "The admin of the config")]
+    #[account(1, writable, name = "config", desc = "The config account")]
+    SetPaused {
+        /// true to halt new trades
+        paused: bool,
+    },
+    /// Proposes a new admin, who becomes admin once they accept
+    #[account(0, signer, name = "admin", desc = "The admin of the config")]
+    #[account(1, writable, name = "config", desc = "The config account")]
+    #[account(2, name = "new_admin", desc = "The proposed admin")]
+    TransferAdmin,
+    /// Completes an admin handover started by `TransferAdmin`
+    #[account(0, signer, name = "new_admin", desc = "The proposed admin")]
+    #[account(1, writable, name = "config", desc = "The config account")]
+    AcceptAdmin,
+    /// Deposits token B against an escrow, the first phase of a two-phase trade.
+    /// The deposit pays for the whole remaining amount of token A, `Settle` then swaps both vaults
+    /// and the counterparty can take the deposit back with `WithdrawDeposit` until then.
+    /// Transfer fees of token B are paid on top by the counterparty, for the deposit and for the
+    /// settlement paying the initializer, so the initializer receives the full price.
+    /// Native SOL escrows are not supported.
+    #[account(
+        0,
+        signer,
+        writable,
+        name = "counterparty",
+        desc = "The account of the person depositing token B, pays for the deposit accounts"
+    )]
+    #[account(
+        1,
+        name = "escrow_state",
+        desc = "The escrow account holding the escrow info"
+    )]
+    #[account(
+        2,
+        writable,
+        name = "deposit_state",
+        desc = "The deposit account of the escrow, it will hold the deposit info"
+    )]
+    #[account(
+        3,
+        writable,
+        name = "token_b_vault",
+        desc = "Temporary token B account owned by the deposit account"
+    )]
+    #[account(
+        4,
+        writable,
+        name = "token_b_founder",
+        desc = "The counterparty's token account for the token they deposit"
+    )]
+    #[account(5, name = "mint_b", desc = "The mint of token B")]
+    #[account(6, name = "token_b_program", desc = "The token program owning mint B")]
+    #[account(
+        7,
+        name = "associated_token_program",
+        desc = "The associated token program"
+    )]
+    #[account(8, name = "system_program", desc = "The system program")]
+    #[account(
+        9,
+        name = "config",
+        desc = "The config account, deposits are refused while the program is paused"
+    )]
+    Deposit {
+        /// the amount the counterparty expects to be paid in the other token, checked against the escrow
+        amount: u64,
+        /// proof that the counterparty belongs to the allowlist of the escrow, empty for an open escrow
+        proof: Vec<[u8; 32]>,
     },
+    /// Swaps the vaults of an escrow and of its deposit, closing both, callable by either side.
+    /// The transfer fee of paying token B to the initializer was prepaid with the deposit, so the
+    /// initializer receives the full price, the transfer fee of token A is deducted from what the
+    /// counterparty receives.
+    #[account(
+        0,
+        signer,
+        writable,
+        name = "settler",
+        desc = "The initializer or the counterparty, pays for missing token accounts"
+    )]
+    #[account(
+        1,
+        writable,
+        name = "initializer",
+        desc = "The initializer of the escrow, receives the rent of the escrow accounts"
+    )]
+    #[account(
+        2,
+        writable,
+        name = "counterparty",
+        desc = "The counterparty of the deposit, receives the rent of the deposit accounts"
+    )]
+    #[account(
+        3,
+        writable,
+        name = "escrow_state",
+        desc = "The escrow account holding the escrow info"
+    )]
+    #[account(
+        4,
+        writable,
+        name = "token_a_vault",
+        desc = "The vault ATA owned by the escrow account to empty and close"
+    )]
+    #[account(
+        5,
+        writable,
+        name = "deposit_state",
+        desc = "The deposit account holding the deposit info"
+    )]
+    #[account(
+        6,
+        writable,
+        name = "token_b_vault",
+        desc = "The vault ATA owned by the deposit account to empty and close"
+    )]
+    #[account(
+        7,
+        writable,
+        name = "token_a_receiver",
+        desc = "The counterparty's token account that will receive token A"
+    )]
+    #[account(
+        8,
+        writable,
+        name = "token_b_receiver",
+        desc = "The initializer's token B ATA that will receive token B"
+    )]
+    #[account(
+        9,
+        name = "mint_a",
+        desc = "The mint of token A, writable when it charges a transfer fee, the fees withheld by the vault being harvested to it on close"
+    )]
+    #[account(
+        10,
+        name = "mint_b",
+        desc = "The mint of token B, writable when it charges a transfer fee, the fees withheld by the vault being harvested to it on close"
+    )]
+    #[account(11, name = "token_a_program", desc = "The token program owning mint A")]
+    #[account(12, name = "token_b_program", desc = "The token program owning mint B")]
+    #[account(
+        13,
+        name = "associated_token_program",
+        desc = "The associated token program"
+    )]
+    #[account(14, name = "system_program", desc = "The system program")]
+    #[account(
+        15,
+        name = "config",
+        desc = "The config account, the protocol fee is skipped while it is not initialized and settlements are refused while the program is paused"
+    )]
+    #[account(
+        16,
+        writable,
+        name = "fee_receiver",
+        desc = "The fee recipient's ATA for the mint of the fee side, created by the settler when missing"
+    )]
+    #[account(
+        17,
+        name = "fee_recipient",
+        desc = "The fee recipient wallet of the config, owning the fee receiver"
+    )]
+    Settle,
+    /// Refunds a deposit to its counterparty before settlement and closes it
+    #[account(
+        0,
+        signer,
+        writable,
+        name = "counterparty",
+        desc = "The counterparty of the deposit, receives the rent of the closed accounts"
+    )]
+    #[account(
+        1,
+        writable,
+        name = "deposit_state",
+        desc = "The deposit account holding the deposit info"
+    )]
+    #[account(
+        2,
+        writable,
+        name = "token_b_vault",
+        desc = "The vault ATA owned by the deposit account to refund and close"
+    )]
+    #[account(
+        3,
+        writable,
+        name = "token_b_receiver",
+        desc = "The counterparty's token account that will receive token B back"
+    )]
+    #[account(
+        4,
+        name = "mint_b",
+        desc = "The mint of token B, writable when it charges a transfer fee, the fees withheld by the vault being harvested to it on close"
+    )]
+    #[account(5, name = "token_b_program", desc = "The token program owning mint B")]
+    WithdrawDeposit,
 }
 
 impl EscrowInstruction {
+    /// Number of instructions, their tags run from 0 to `INSTRUCTION_COUNT - 1`
+    pub const INSTRUCTION_COUNT: u8 = 13;
+
     /// Unpacks a byte buffer into a [EscrowInstruction](enum.EscrowInstruction.html).
+    ///
+    /// The buffer must hold exactly one instruction, a payload ending early fails with
+    /// `InstructionDataTooShort` and trailing bytes fail with `InstructionDataTooLong`.
     pub fn unpack(input: &[u8]) -> Result<Self, EscrowError> {
-        let (tag, rest) = match input.split_first() {
-            Some(s) => s,
-            None => return Err(EscrowError::InvalidInstructionType),
-        };
-
-        Ok(match tag {
-            0 => {
-                let (amount_to_trade, rest) = Self::unpack_u64(rest)?;
-                let (amount_expected, rest) = Self::unpack_u64(rest)?;
-                let (seed, _) = Self::unpack_u64(rest)?;
-                Self::InitEscrow {
-                    amount_expected,
-                    seed,
-                    amount_to_trade,
-                }
-            }
-            1 => Self::Exchange {
-                amount: Self::unpack_amount(rest)?,
-            },
+        match input.first() {
+            Some(tag) if *tag < Self::INSTRUCTION_COUNT => {}
             _ => return Err(EscrowError::InvalidInstructionType),
-        })
-    }
-
-    fn unpack_amount(input: &[u8]) -> Result<u64, EscrowError> {
-        return match input
-            .get(..8)
-            .and_then(|slice| slice.try_into().ok())
-            .map(u64::from_le_bytes)
-        {
-            Some(a) => Ok(a),
-            None => Err(EscrowError::InvalidInstructionData),
+        }
+        let mut reader = InstructionReader {
+            data: input,
+            truncated: false,
         };
+        match Self::deserialize_reader(&mut reader) {
+            Ok(_) if !reader.data.is_empty() => Err(EscrowError::InstructionDataTooLong),
+            Ok(instruction) => Ok(instruction),
+            Err(_) if reader.truncated => Err(EscrowError::InstructionDataTooShort),
+            Err(_) => Err(EscrowError::InvalidInstructionData),
+        }
     }
-    fn unpack_u64(input: &[u8]) -> Result<(u64, &[u8]), EscrowError> {
-        let (amount, rest) = input.split_at(8);
-        let amount = amount
-            .try_into()
-            .ok()
-            .map(u64::from_le_bytes)
-            .ok_or(EscrowError::InvalidInstructionData)?;
-        Ok((amount, rest))
+
+    /// Packs a [EscrowInstruction](enum.EscrowInstruction.html) into a byte buffer.
+    pub fn pack(&self) -> Vec<u8> {
+        borsh::to_vec(self).expect("serializing into a Vec never fails")
     }
+
     pub fn print_instruction_name(self) -> EscrowInstruction {
         msg!(self.as_ref());
         self
     }
 }
+
+/// Reads instruction data, remembering whether the decoder asked for more bytes than there are
+struct InstructionReader<'a> {
+    data: &'a [u8],
+    truncated: bool,
+}
+impl Read for InstructionReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.len() > self.data.len() {
+            self.truncated = true;
+        }
+        self.data.read(buf)
+    }
+}
+
+/// Creates an `InitEscrow` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn init_escrow(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    mint_a: &Pubkey,
+    mint_b: &Pubkey,
+    token_a_founder: &Pubkey,
+    token_a_program: &Pubkey,
+    amount_to_trade: u64,
+    amount_expected: u64,
+    seed: u64,
+    expiry: Option<i64>,
+    native_side: NativeSide,
+    allowed_taker: Option<Pubkey>,
+    taker_merkle_root: Option<[u8; 32]>,
+) -> Instruction {
+    let (escrow_state, _) = find_escrow_address_with_program_id(initializer, seed, program_id);
+    let (config, _) = find_config_address_with_program_id(program_id);
+    let data = EscrowInstruction::InitEscrow {
+        amount_to_trade,
+        amount_expected,
+        seed,
+        expiry,
+        native_side,
+        allowed_taker,
+        taker_merkle_root,
+    }
+    .pack();
+    let accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(escrow_state, false),
+        AccountMeta::new(
+            token_account_address(&escrow_state, mint_a, token_a_program),
+            false,
+        ),
+        AccountMeta::new(*token_a_founder, false),
+        AccountMeta::new_readonly(*mint_a, false),
+        AccountMeta::new_readonly(*mint_b, false),
+        AccountMeta::new_readonly(*token_a_program, false),
+        AccountMeta::new_readonly(spl_associated_token_account::ID, false),
+        AccountMeta::new_readonly(system_program::ID, false),
+        AccountMeta::new_readonly(config, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    }
+}
+
+/// Creates an `Exchange` instruction, token A is received in the taker's ATA
+/// and the protocol fee, if any, is sent to the ATA of `fee_recipient`.
+#[allow(clippy::too_many_arguments)]
+pub fn exchange(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    initializer: &Pubkey,
+    mint_a: &Pubkey,
+    mint_b: &Pubkey,
+    token_b_founder: &Pubkey,
+    token_a_program: &Pubkey,
+    token_b_program: &Pubkey,
+    seed: u64,
+    amount: u64,
+    fill_amount: u64,
+    min_amount_a: u64,
+    proof: Vec<[u8; 32]>,
+    fee_recipient: &Pubkey,
+    fee_side: FeeSide,
+) -> Instruction {
+    let (escrow_state, _) = find_escrow_address_with_program_id(initializer, seed, program_id);
+    let (config, _) = find_config_address_with_program_id(program_id);
+    let data = EscrowInstruction::Exchange {
+        amount,
+        fill_amount,
+        min_amount_a,
+        proof,
+    }
+    .pack();
+    let accounts = vec![
+        AccountMeta::new(*taker, true),
+        AccountMeta::new(*initializer, false),
+        AccountMeta::new(escrow_state, false),
+        AccountMeta::new(
+            token_account_address(&escrow_state, mint_a, token_a_program),
+            false,
+        ),
+        AccountMeta::new(token_account_address(taker, mint_a, token_a_program), false),
+        AccountMeta::new(
+            token_account_address(initializer, mint_b, token_b_program),
+            false,
+        ),
+        AccountMeta::new(*token_b_founder, false),
+        harvest_mint_meta(mint_a, token_a_program),
+        AccountMeta::new_readonly(*mint_b, false),
+        AccountMeta::new_readonly(*token_a_program, false),
+        AccountMeta::new_readonly(*token_b_program, false),
+        AccountMeta::new_readonly(spl_associated_token_account::ID, false),
+        AccountMeta::new_readonly(system_program::ID, false),
+        AccountMeta::new_readonly(config, false),
+        AccountMeta::new(
+            match fee_side {
+                FeeSide::A => token_account_address(fee_recipient, mint_a, token_a_program),
+                FeeSide::B => token_account_address(fee_recipient, mint_b, token_b_program),
+            },
+            false,
+        ),
+        AccountMeta::new_readonly(*fee_recipient, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    }
+}
+
+/// A mint whose vault the instruction may close, writable under token-2022 where it may charge
+/// a transfer fee whose withheld amount is harvested into it, read-only otherwise so the trade
+/// does not write-lock the mint
+fn harvest_mint_meta(mint: &Pubkey, token_program: &Pubkey) -> AccountMeta {
+    if token_program.eq(&spl_token_2022::ID) {
+        AccountMeta::new(*mint, false)
+    } else {
+        AccountMeta::new_readonly(*mint, false)
+    }
+}
+
+/// Creates a `Cancel` instruction.
+pub fn cancel(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    mint_a: &Pubkey,
+    token_a_receiver: &Pubkey,
+    token_a_program: &Pubkey,
+    seed: u64,
+) -> Instruction {
+    let (escrow_state, _) = find_escrow_address_with_program_id(initializer, seed, program_id);
+    let accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(escrow_state, false),
+        AccountMeta::new(
+            token_account_address(&escrow_state, mint_a, token_a_program),
+            false,
+        ),
+        AccountMeta::new(*token_a_receiver, false),
+        harvest_mint_meta(mint_a, token_a_program),
+        AccountMeta::new_readonly(*token_a_program, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::Cancel.pack(),
+    }
+}
+
+/// Creates an `Expire` instruction, token A is refunded to the initializer's ATA.
+pub fn expire(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    initializer: &Pubkey,
+    mint_a: &Pubkey,
+    token_a_program: &Pubkey,
+    seed: u64,
+) -> Instruction {
+    let (escrow_state, _) = find_escrow_address_with_program_id(initializer, seed, program_id);
+    let accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new(*initializer, false),
+        AccountMeta::new(escrow_state, false),
+        AccountMeta::new(
+            token_account_address(&escrow_state, mint_a, token_a_program),
+            false,
+        ),
+        AccountMeta::new(
+            token_account_address(initializer, mint_a, token_a_program),
+            false,
+        ),
+        harvest_mint_meta(mint_a, token_a_program),
+        AccountMeta::new_readonly(*token_a_program, false),
+        AccountMeta::new_readonly(spl_associated_token_account::ID, false),
+        AccountMeta::new_readonly(system_program::ID, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::Expire.pack(),
+    }
+}
+
+/// Creates a `MigrateEscrow` instruction.
+pub fn migrate_escrow(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    mint_a: &Pubkey,
+    token_a_program: &Pubkey,
+    seed: u64,
+) -> Instruction {
+    let (escrow_state, _) = find_escrow_address_with_program_id(initializer, seed, program_id);
+    let token_a_vault = token_account_address(&escrow_state, mint_a, token_a_program);
+    let accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(escrow_state, false),
+        AccountMeta::new_readonly(token_a_vault, false),
+        AccountMeta::new_readonly(system_program::ID, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::MigrateEscrow.pack(),
+    }
+}
+
+/// Creates an `InitConfig` instruction.
+pub fn init_config(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    fee_recipient: &Pubkey,
+    fee_bps: u16,
+    fee_side: FeeSide,
+) -> Instruction {
+    let (config, _) = find_config_address_with_program_id(program_id);
+    let (program_data, _) = find_program_data_address_with_program_id(program_id);
+    let accounts = vec![
+        AccountMeta::new(*admin, true),
+        AccountMeta::new(config, false),
+        AccountMeta::new_readonly(*fee_recipient, false),
+        AccountMeta::new_readonly(program_data, false),
+        AccountMeta::new_readonly(system_program::ID, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::InitConfig { fee_bps, fee_side }.pack(),
+    }
+}
+
+/// Creates an `UpdateConfig` instruction.
+pub fn update_config(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    fee_recipient: &Pubkey,
+    fee_bps: u16,
+    fee_side: FeeSide,
+) -> Instruction {
+    let (config, _) = find_config_address_with_program_id(program_id);
+    let accounts = vec![
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new(config, false),
+        AccountMeta::new_readonly(*fee_recipient, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::UpdateConfig { fee_bps, fee_side }.pack(),
+    }
+}
+
+/// Creates a `SetPaused` instruction.
+pub fn set_paused(program_id: &Pubkey, admin: &Pubkey, paused: bool) -> Instruction {
+    let (config, _) = find_config_address_with_program_id(program_id);
+    let accounts = vec![
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new(config, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::SetPaused { paused }.pack(),
+    }
+}
+
+/// Creates a `TransferAdmin` instruction.
+pub fn transfer_admin(program_id: &Pubkey, admin: &Pubkey, new_admin: &Pubkey) -> Instruction {
+    let (config, _) = find_config_address_with_program_id(program_id);
+    let accounts = vec![
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new(config, false),
+        AccountMeta::new_readonly(*new_admin, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::TransferAdmin.pack(),
+    }
+}
+
+/// Creates an `AcceptAdmin` instruction.
+pub fn accept_admin(program_id: &Pubkey, new_admin: &Pubkey) -> Instruction {
+    let (config, _) = find_config_address_with_program_id(program_id);
+    let accounts = vec![
+        AccountMeta::new_readonly(*new_admin, true),
+        AccountMeta::new(config, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::AcceptAdmin.pack(),
+    }
+}
+
+/// Creates a `Deposit` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn deposit(
+    program_id: &Pubkey,
+    counterparty: &Pubkey,
+    initializer: &Pubkey,
+    mint_b: &Pubkey,
+    token_b_founder: &Pubkey,
+    token_b_program: &Pubkey,
+    seed: u64,
+    amount: u64,
+    proof: Vec<[u8; 32]>,
+) -> Instruction {
+    let (escrow_state, _) = find_escrow_address_with_program_id(initializer, seed, program_id);
+    let (deposit_state, _) = find_deposit_address_with_program_id(&escrow_state, program_id);
+    let (config, _) = find_config_address_with_program_id(program_id);
+    let accounts = vec![
+        AccountMeta::new(*counterparty, true),
+        AccountMeta::new_readonly(escrow_state, false),
+        AccountMeta::new(deposit_state, false),
+        AccountMeta::new(
+            token_account_address(&deposit_state, mint_b, token_b_program),
+            false,
+        ),
+        AccountMeta::new(*token_b_founder, false),
+        AccountMeta::new_readonly(*mint_b, false),
+        AccountMeta::new_readonly(*token_b_program, false),
+        AccountMeta::new_readonly(spl_associated_token_account::ID, false),
+        AccountMeta::new_readonly(system_program::ID, false),
+        AccountMeta::new_readonly(config, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::Deposit { amount, proof }.pack(),
+    }
+}
+
+/// Creates a `Settle` instruction, token A is received in the counterparty's ATA
+/// and the protocol fee, if any, is sent to the ATA of `fee_recipient`.
+#[allow(clippy::too_many_arguments)]
+pub fn settle(
+    program_id: &Pubkey,
+    settler: &Pubkey,
+    initializer: &Pubkey,
+    counterparty: &Pubkey,
+    mint_a: &Pubkey,
+    mint_b: &Pubkey,
+    token_a_program: &Pubkey,
+    token_b_program: &Pubkey,
+    seed: u64,
+    fee_recipient: &Pubkey,
+    fee_side: FeeSide,
+) -> Instruction {
+    let (escrow_state, _) = find_escrow_address_with_program_id(initializer, seed, program_id);
+    let (deposit_state, _) = find_deposit_address_with_program_id(&escrow_state, program_id);
+    let (config, _) = find_config_address_with_program_id(program_id);
+    let accounts = vec![
+        AccountMeta::new(*settler, true),
+        AccountMeta::new(*initializer, false),
+        AccountMeta::new(*counterparty, false),
+        AccountMeta::new(escrow_state, false),
+        AccountMeta::new(
+            token_account_address(&escrow_state, mint_a, token_a_program),
+            false,
+        ),
+        AccountMeta::new(deposit_state, false),
+        AccountMeta::new(
+            token_account_address(&deposit_state, mint_b, token_b_program),
+            false,
+        ),
+        AccountMeta::new(
+            token_account_address(counterparty, mint_a, token_a_program),
+            false,
+        ),
+        AccountMeta::new(
+            token_account_address(initializer, mint_b, token_b_program),
+            false,
+        ),
+        harvest_mint_meta(mint_a, token_a_program),
+        harvest_mint_meta(mint_b, token_b_program),
+        AccountMeta::new_readonly(*token_a_program, false),
+        AccountMeta::new_readonly(*token_b_program, false),
+        AccountMeta::new_readonly(spl_associated_token_account::ID, false),
+        AccountMeta::new_readonly(system_program::ID, false),
+        AccountMeta::new_readonly(config, false),
+        AccountMeta::new(
+            match fee_side {
+                FeeSide::A => token_account_address(fee_recipient, mint_a, token_a_program),
+                FeeSide::B => token_account_address(fee_recipient, mint_b, token_b_program),
+            },
+            false,
+        ),
+        AccountMeta::new_readonly(*fee_recipient, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::Settle.pack(),
+    }
+}
+
+/// Creates a `WithdrawDeposit` instruction.
+pub fn withdraw_deposit(
+    program_id: &Pubkey,
+    counterparty: &Pubkey,
+    initializer: &Pubkey,
+    mint_b: &Pubkey,
+    token_b_receiver: &Pubkey,
+    token_b_program: &Pubkey,
+    seed: u64,
+) -> Instruction {
+    let (escrow_state, _) = find_escrow_address_with_program_id(initializer, seed, program_id);
+    let (deposit_state, _) = find_deposit_address_with_program_id(&escrow_state, program_id);
+    let accounts = vec![
+        AccountMeta::new(*counterparty, true),
+        AccountMeta::new(deposit_state, false),
+        AccountMeta::new(
+            token_account_address(&deposit_state, mint_b, token_b_program),
+            false,
+        ),
+        AccountMeta::new(*token_b_receiver, false),
+        harvest_mint_meta(mint_b, token_b_program),
+        AccountMeta::new_readonly(*token_b_program, false),
+    ];
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::WithdrawDeposit.pack(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn instruction() -> impl Strategy<Value = EscrowInstruction> {
+        let native_side = prop_oneof![
+            Just(NativeSide::None),
+            Just(NativeSide::A),
+            Just(NativeSide::B)
+        ];
+        let fee_side = prop_oneof![Just(FeeSide::A), Just(FeeSide::B)];
+        let pubkey = any::<[u8; 32]>().prop_map(Pubkey::new_from_array);
+        prop_oneof![
+            (
+                any::<(u64, u64, u64)>(),
+                any::<Option<i64>>(),
+                native_side,
+                proptest::option::of(pubkey),
+                any::<Option<[u8; 32]>>(),
+            )
+                .prop_map(
+                    |(
+                        (amount_to_trade, amount_expected, seed),
+                        expiry,
+                        native_side,
+                        allowed_taker,
+                        taker_merkle_root,
+                    )| EscrowInstruction::InitEscrow {
+                        amount_to_trade,
+                        amount_expected,
+                        seed,
+                        expiry,
+                        native_side,
+                        allowed_taker,
+                        taker_merkle_root,
+                    }
+                ),
+            (
+                any::<(u64, u64, u64)>(),
+                proptest::collection::vec(any::<[u8; 32]>(), 0..8)
+            )
+                .prop_map(|((amount, fill_amount, min_amount_a), proof)| {
+                    EscrowInstruction::Exchange {
+                        amount,
+                        fill_amount,
+                        min_amount_a,
+                        proof,
+                    }
+                }),
+            Just(EscrowInstruction::Cancel),
+            Just(EscrowInstruction::Expire),
+            Just(EscrowInstruction::MigrateEscrow),
+            (any::<u16>(), fee_side.clone()).prop_map(|(fee_bps, fee_side)| {
+                EscrowInstruction::InitConfig { fee_bps, fee_side }
+            }),
+            (any::<u16>(), fee_side).prop_map(|(fee_bps, fee_side)| {
+                EscrowInstruction::UpdateConfig { fee_bps, fee_side }
+            }),
+            any::<bool>().prop_map(|paused| EscrowInstruction::SetPaused { paused }),
+            Just(EscrowInstruction::TransferAdmin),
+            Just(EscrowInstruction::AcceptAdmin),
+            (
+                any::<u64>(),
+                proptest::collection::vec(any::<[u8; 32]>(), 0..8)
+            )
+                .prop_map(|(amount, proof)| EscrowInstruction::Deposit { amount, proof }),
+            Just(EscrowInstruction::Settle),
+            Just(EscrowInstruction::WithdrawDeposit),
+        ]
+    }
+
+    #[test]
+    fn unpack_rejects_unknown_tags() {
+        assert_eq!(
+            EscrowInstruction::unpack(&[]),
+            Err(EscrowError::InvalidInstructionType)
+        );
+        assert_eq!(
+            EscrowInstruction::unpack(&[EscrowInstruction::INSTRUCTION_COUNT]),
+            Err(EscrowError::InvalidInstructionType)
+        );
+    }
+
+    #[test]
+    fn unpack_rejects_malformed_values() {
+        // `SetPaused` with a bool that is neither 0 nor 1
+        assert_eq!(
+            EscrowInstruction::unpack(&[7, 2]),
+            Err(EscrowError::InvalidInstructionData)
+        );
+        // `InitConfig` with an unknown fee side
+        assert_eq!(
+            EscrowInstruction::unpack(&[5, 0, 0, 2]),
+            Err(EscrowError::InvalidInstructionData)
+        );
+    }
+
+    #[test]
+    fn unpack_rejects_oversized_proof_length() {
+        // a proof claiming u32::MAX nodes without carrying them
+        let mut data = vec![1];
+        data.extend_from_slice(&[0; 24]);
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(
+            EscrowInstruction::unpack(&data),
+            Err(EscrowError::InstructionDataTooShort)
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn unpack_never_panics(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+            let _ = EscrowInstruction::unpack(&data);
+        }
+
+        #[test]
+        fn pack_round_trips(instruction in instruction()) {
+            prop_assert_eq!(EscrowInstruction::unpack(&instruction.pack()), Ok(instruction));
+        }
+
+        #[test]
+        fn unpack_rejects_truncated_data(instruction in instruction(), cut in 1usize..64) {
+            let data = instruction.pack();
+            prop_assume!(data.len() > 1);
+            let len = data.len().saturating_sub(cut).max(1);
+            prop_assert_eq!(
+                EscrowInstruction::unpack(&data[..len]),
+                Err(EscrowError::InstructionDataTooShort)
+            );
+        }
+
+        #[test]
+        fn unpack_rejects_trailing_data(
+            instruction in instruction(),
+            trailing in proptest::collection::vec(any::<u8>(), 1..64),
+        ) {
+            let mut data = instruction.pack();
+            data.extend_from_slice(&trailing);
+            prop_assert_eq!(
+                EscrowInstruction::unpack(&data),
+                Err(EscrowError::InstructionDataTooLong)
+            );
+        }
+    }
+}
@@ -1,8 +1,12 @@
 pub mod accounts;
 pub mod error;
+pub mod events;
 pub mod instruction;
+pub mod merkle;
+pub mod pda;
 pub mod processor;
 pub mod state;
+pub mod validation;
 use solana_program::{entrypoint::ProgramResult, program_error::ProgramError, pubkey::Pubkey};
 #[cfg(not(feature = "no-entrypoint"))]
 pub mod entrypoint;
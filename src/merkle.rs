@@ -0,0 +1,23 @@
+use solana_program::{keccak, pubkey::Pubkey};
+
+/// Leaf of a taker allowlist, the keccak hash of the taker's pubkey
+pub fn taker_leaf(taker: &Pubkey) -> [u8; 32] {
+    keccak::hash(taker.as_ref()).to_bytes()
+}
+
+/// Hashes two nodes in sorted order, so proofs don't need to carry left/right flags
+pub fn hash_pair(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    if a <= b {
+        keccak::hashv(&[a, b]).to_bytes()
+    } else {
+        keccak::hashv(&[b, a]).to_bytes()
+    }
+}
+
+/// Returns true if `proof` links the leaf of `taker` to `root`
+pub fn verify_taker_proof(proof: &[[u8; 32]], root: &[u8; 32], taker: &Pubkey) -> bool {
+    let computed = proof
+        .iter()
+        .fold(taker_leaf(taker), |node, sibling| hash_pair(&node, sibling));
+    computed.eq(root)
+}
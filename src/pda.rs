@@ -0,0 +1,125 @@
+use solana_program::{
+    bpf_loader_upgradeable,
+    pubkey::{Pubkey, PubkeyError},
+    system_program,
+};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+
+use crate::{
+    id,
+    state::{DepositState, EscrowState},
+};
+
+/// Finds the escrow state address of the given initializer and seed
+pub fn find_escrow_address(initializer: &Pubkey, seed: u64) -> (Pubkey, u8) {
+    find_escrow_address_with_program_id(initializer, seed, &id())
+}
+
+/// Finds the escrow state address of the given initializer and seed under `program_id`
+pub fn find_escrow_address_with_program_id(
+    initializer: &Pubkey,
+    seed: u64,
+    program_id: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[&seed.to_le_bytes(), initializer.as_ref()], program_id)
+}
+
+/// Creates the escrow state address from an already known bump
+pub fn create_escrow_address(
+    initializer: &Pubkey,
+    seed: u64,
+    bump: u8,
+) -> Result<Pubkey, PubkeyError> {
+    Pubkey::create_program_address(&[&seed.to_le_bytes(), initializer.as_ref(), &[bump]], &id())
+}
+
+/// Seed of the global config address
+pub const CONFIG_SEED: &[u8] = b"config";
+
+/// Finds the global config address
+pub fn find_config_address() -> (Pubkey, u8) {
+    find_config_address_with_program_id(&id())
+}
+
+/// Finds the global config address under `program_id`
+pub fn find_config_address_with_program_id(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CONFIG_SEED], program_id)
+}
+
+/// Finds the program data account of this program, which records its upgrade authority
+pub fn find_program_data_address() -> (Pubkey, u8) {
+    find_program_data_address_with_program_id(&id())
+}
+
+/// Finds the program data account of the upgradeable program `program_id`
+pub fn find_program_data_address_with_program_id(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::ID)
+}
+
+/// Seed prefix of the deposit address of an escrow
+pub const DEPOSIT_SEED: &[u8] = b"deposit";
+
+/// Finds the address of the counterparty deposit of the given escrow
+pub fn find_deposit_address(escrow: &Pubkey) -> (Pubkey, u8) {
+    find_deposit_address_with_program_id(escrow, &id())
+}
+
+/// Finds the address of the counterparty deposit of the given escrow under `program_id`
+pub fn find_deposit_address_with_program_id(escrow: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[DEPOSIT_SEED, escrow.as_ref()], program_id)
+}
+
+/// Creates the deposit address from an already known bump
+pub fn create_deposit_address(escrow: &Pubkey, bump: u8) -> Result<Pubkey, PubkeyError> {
+    Pubkey::create_program_address(&[DEPOSIT_SEED, escrow.as_ref(), &[bump]], &id())
+}
+
+/// Address of the token account of `wallet` for `mint`, the ATA for a token or the wallet itself
+/// for native SOL, whose token program is the system program
+pub fn token_account_address(wallet: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Pubkey {
+    if token_program.eq(&system_program::ID) {
+        *wallet
+    } else {
+        get_associated_token_address_with_program_id(wallet, mint, token_program)
+    }
+}
+
+/// Owned signer seeds of an escrow state, see [escrow_signer_seeds]
+pub struct EscrowSignerSeeds {
+    seed: [u8; 8],
+    initializer: [u8; 32],
+    bump: [u8; 1],
+}
+impl EscrowSignerSeeds {
+    pub fn as_seeds(&self) -> [&[u8]; 3] {
+        [&self.seed, &self.initializer, &self.bump]
+    }
+}
+
+/// Returns the seeds the escrow state signs CPIs with
+pub fn escrow_signer_seeds(state: &EscrowState) -> EscrowSignerSeeds {
+    EscrowSignerSeeds {
+        seed: state.seed.to_le_bytes(),
+        initializer: state.initializer_pubkey.to_bytes(),
+        bump: [state.bump],
+    }
+}
+
+/// Owned signer seeds of a deposit state, see [deposit_signer_seeds]
+pub struct DepositSignerSeeds {
+    escrow: [u8; 32],
+    bump: [u8; 1],
+}
+impl DepositSignerSeeds {
+    pub fn as_seeds(&self) -> [&[u8]; 3] {
+        [DEPOSIT_SEED, &self.escrow, &self.bump]
+    }
+}
+
+/// Returns the seeds the deposit state signs CPIs with
+pub fn deposit_signer_seeds(state: &DepositState) -> DepositSignerSeeds {
+    DepositSignerSeeds {
+        escrow: state.escrow.to_bytes(),
+        bump: [state.bump],
+    }
+}
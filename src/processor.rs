@@ -1,18 +1,39 @@
 use crate::{
-    accounts::ExchangeAccount, accounts::InitEscrowAccount, error::EscrowError, id,
-    instruction::EscrowInstruction, state::EscrowState,
+    accounts::{
+        unpack_config, AcceptAdminAccount, CancelEscrowAccount, DepositEscrowAccount,
+        ExchangeAccount, ExpireEscrowAccount, InitConfigAccount, InitEscrowAccount,
+        MigrateEscrowAccount, SetPausedAccount, SettleEscrowAccount, TransferAdminAccount,
+        UpdateConfigAccount, WithdrawDepositAccount,
+    },
+    error::EscrowError,
+    events::{
+        DepositWithdrawn, EscrowCancelled, EscrowCreated, EscrowDeposited, EscrowEvent,
+        EscrowExchanged,
+    },
+    id,
+    instruction::EscrowInstruction,
+    pda::{deposit_signer_seeds, escrow_signer_seeds, CONFIG_SEED},
+    state::{
+        Config, DepositState, EscrowAccount, EscrowState, FeeSide, MintAccount, NativeSide,
+        TokenAccount,
+    },
 };
 use solana_program::{
     account_info::AccountInfo,
+    clock::Clock,
     program::{invoke, invoke_signed},
     program_pack::Pack,
     pubkey::Pubkey,
     rent::Rent,
     system_instruction::{self},
+    system_program,
     sysvar::Sysvar,
 };
 use spl_associated_token_account::instruction::create_associated_token_account_idempotent;
-use spl_token::instruction::{close_account, transfer_checked};
+use spl_token_2022::{
+    extension::transfer_fee::instruction::harvest_withheld_tokens_to_mint,
+    instruction::{close_account, transfer_checked},
+};
 pub struct Processor;
 
 impl Processor {
@@ -20,184 +41,1067 @@ impl Processor {
         accounts: &'a [AccountInfo<'a>],
         instruction_data: &[u8],
     ) -> Result<(), EscrowError> {
-        return match EscrowInstruction::unpack(instruction_data) {
-            Ok(s) => match s {
-                EscrowInstruction::Exchange { amount } => Self::process_exchange(accounts, amount),
-                EscrowInstruction::InitEscrow {
-                    amount_to_trade,
-                    amount_expected,
-                    seed,
-                } => Self::process_init_escrow(accounts, amount_to_trade, amount_expected, seed),
-            },
-            Err(e) => Err(e),
-        };
+        let instruction = EscrowInstruction::unpack(instruction_data)?;
+        // new trades are halted while the program is paused, refunds are not
+        match instruction {
+            EscrowInstruction::InitEscrow { .. } => {
+                Self::check_not_paused(accounts, InitEscrowAccount::CONFIG_INDEX)?
+            }
+            EscrowInstruction::Exchange { .. } => {
+                Self::check_not_paused(accounts, ExchangeAccount::CONFIG_INDEX)?
+            }
+            EscrowInstruction::Deposit { .. } => {
+                Self::check_not_paused(accounts, DepositEscrowAccount::CONFIG_INDEX)?
+            }
+            EscrowInstruction::Settle => {
+                Self::check_not_paused(accounts, SettleEscrowAccount::CONFIG_INDEX)?
+            }
+            _ => {}
+        }
+        match instruction {
+            EscrowInstruction::Exchange {
+                amount,
+                fill_amount,
+                min_amount_a,
+                proof,
+            } => Self::process_exchange(accounts, amount, fill_amount, min_amount_a, proof),
+            EscrowInstruction::InitEscrow {
+                amount_to_trade,
+                amount_expected,
+                seed,
+                expiry,
+                native_side,
+                allowed_taker,
+                taker_merkle_root,
+            } => Self::process_init_escrow(
+                accounts,
+                amount_to_trade,
+                amount_expected,
+                seed,
+                expiry,
+                native_side,
+                allowed_taker,
+                taker_merkle_root,
+            ),
+            EscrowInstruction::Cancel => Self::process_cancel(accounts),
+            EscrowInstruction::Expire => Self::process_expire(accounts),
+            EscrowInstruction::MigrateEscrow => Self::process_migrate_escrow(accounts),
+            EscrowInstruction::InitConfig { fee_bps, fee_side } => {
+                Self::process_init_config(accounts, fee_bps, fee_side)
+            }
+            EscrowInstruction::UpdateConfig { fee_bps, fee_side } => {
+                Self::process_update_config(accounts, fee_bps, fee_side)
+            }
+            EscrowInstruction::SetPaused { paused } => Self::process_set_paused(accounts, paused),
+            EscrowInstruction::TransferAdmin => Self::process_transfer_admin(accounts),
+            EscrowInstruction::AcceptAdmin => Self::process_accept_admin(accounts),
+            EscrowInstruction::Deposit { amount, proof } => {
+                Self::process_deposit(accounts, amount, proof)
+            }
+            EscrowInstruction::Settle => Self::process_settle(accounts),
+            EscrowInstruction::WithdrawDeposit => Self::process_withdraw_deposit(accounts),
+        }
+    }
+    /// Fails if the config at `config_index` of `accounts` has the program paused
+    fn check_not_paused(accounts: &[AccountInfo], config_index: usize) -> Result<(), EscrowError> {
+        let config = accounts
+            .get(config_index)
+            .ok_or(EscrowError::NotEnoughAccountKeys)?;
+        match unpack_config(config)? {
+            Some(config) if config.paused => Err(EscrowError::ProgramPaused),
+            _ => Ok(()),
+        }
     }
+    #[allow(clippy::too_many_arguments)]
     fn process_init_escrow<'a>(
         accounts: &'a [AccountInfo<'a>],
         amount_to_trade: u64,
         amount_expected: u64,
         seed: u64,
+        expiry: Option<i64>,
+        native_side: NativeSide,
+        allowed_taker: Option<Pubkey>,
+        taker_merkle_root: Option<[u8; 32]>,
     ) -> Result<(), EscrowError> {
-        let ctx = InitEscrowAccount::unpack(accounts)?;
+        let ctx = InitEscrowAccount::unpack(accounts, seed, native_side)?;
 
-        // derived the key from seed
-        let (escrow_key, bump) = Pubkey::find_program_address(
-            &[&seed.to_le_bytes(), &ctx.initializer.key.to_bytes()],
-            &id(),
-        );
+        // an escrow expecting nothing could never be filled, every fill being priced at zero
+        if amount_to_trade == 0 || amount_expected == 0 {
+            return Err(EscrowError::InvalidTradeAmount);
+        }
+        let clock = Clock::get().map_err(|_| EscrowError::SysvarUnavailable)?;
+        // an escrow must not be born expired
+        if let Some(expiry) = expiry {
+            if clock.unix_timestamp >= expiry {
+                return Err(EscrowError::EscrowExpired);
+            }
+        }
+        // the vault only receives what is left after the transfer fee of token A
+        let deposit_amount = ctx
+            .token_a_mint
+            .transfer_fee(clock.epoch, amount_to_trade)
+            .and_then(|fee| amount_to_trade.checked_sub(fee))
+            .ok_or(EscrowError::ArithmeticOverflow)?;
+        if deposit_amount == 0 {
+            return Err(EscrowError::InvalidTradeAmount);
+        }
+
+        let escrow_state = EscrowState {
+            is_initialized: true,
+            initializer_pubkey: *ctx.initializer.key,
+            mint_a: ctx.token_a_mint.key,
+            mint_b: ctx.token_b_mint.key,
+            expected_amount: amount_expected,
+            bump: ctx.escrow_bump,
+            seed,
+            expiry,
+            deposit_amount,
+            remaining_amount: deposit_amount,
+            native_side,
+            allowed_taker,
+            taker_merkle_root,
+        };
         // access rent info
-        let rent_info = Rent::get().unwrap();
+        let rent_info = Rent::get().map_err(|_| EscrowError::SysvarUnavailable)?;
         // create escrow account
         let create_account_ix = system_instruction::create_account(
             ctx.initializer.key,
             ctx.escrow_state.key,
             rent_info.minimum_balance(EscrowState::LEN),
-            EscrowState::LEN.try_into().unwrap(),
+            EscrowState::LEN
+                .try_into()
+                .map_err(|_| EscrowError::ArithmeticOverflow)?,
             &id(),
         );
         invoke_signed(
             &create_account_ix,
             accounts,
-            &[&[
-                &seed.to_le_bytes(),
-                &ctx.initializer.key.to_bytes(),
-                &bump.to_le_bytes(),
-            ]],
+            &[&escrow_signer_seeds(&escrow_state).as_seeds()],
         )
-        .unwrap();
+        .map_err(EscrowError::CpiFailed)?;
 
-        // create vault account
-        let create_ata_ix = create_associated_token_account_idempotent(
-            ctx.initializer.key,
-            &escrow_key,
-            &ctx.token_a_mint.key,
-            &spl_token::ID,
-        );
-        invoke(&create_ata_ix, accounts).unwrap();
+        if native_side.is_native_a() {
+            // native SOL is held by the escrow account itself
+            let transfer = system_instruction::transfer(
+                ctx.initializer.key,
+                ctx.escrow_state.key,
+                amount_to_trade,
+            );
+            invoke(&transfer, accounts).map_err(EscrowError::CpiFailed)?;
+        } else {
+            // create vault account
+            let create_ata_ix = create_associated_token_account_idempotent(
+                ctx.initializer.key,
+                ctx.escrow_state.key,
+                &ctx.token_a_mint.key,
+                &ctx.token_a_mint.program_id,
+            );
+            invoke(&create_ata_ix, accounts).map_err(EscrowError::CpiFailed)?;
 
-        // transfer token A to vault
-        let transfer = transfer_checked(
-            &spl_token::ID,
-            &ctx.token_a_founder.key,
-            &ctx.token_a_mint.key,
-            &ctx.token_a_vault.key,
-            ctx.initializer.key,
-            &[],
-            amount_to_trade,
-            ctx.token_a_mint.info.decimals,
-        )
-        .unwrap();
-        invoke(&transfer, accounts).unwrap();
+            // transfer token A to vault
+            let transfer = transfer_checked(
+                &ctx.token_a_mint.program_id,
+                &ctx.token_a_founder.key,
+                &ctx.token_a_mint.key,
+                ctx.token_a_vault.key,
+                ctx.initializer.key,
+                &[],
+                amount_to_trade,
+                ctx.token_a_mint.info.decimals,
+            )
+            .map_err(EscrowError::CpiFailed)?;
+            invoke(&transfer, accounts).map_err(EscrowError::CpiFailed)?;
+        }
 
+        let event = EscrowEvent::Created(EscrowCreated {
+            escrow: *ctx.escrow_state.key,
+            initializer: escrow_state.initializer_pubkey,
+            mint_a: escrow_state.mint_a,
+            mint_b: escrow_state.mint_b,
+            deposit_amount,
+            expected_amount: amount_expected,
+            seed,
+            expiry,
+        });
         // update state back on chain
         EscrowState::pack(
-            EscrowState {
-                is_initialized: true,
-                initializer_pubkey: ctx.initializer.key.clone(),
-                mint_a: ctx.token_a_mint.key,
-                mint_b: ctx.token_b_mint.key,
-                expected_amount: amount_expected,
-                bump: bump,
-                seed: seed,
-            },
-            &mut ctx.escrow_state.try_borrow_mut_data().unwrap(),
-        )
-        .unwrap();
+            escrow_state,
+            &mut ctx
+                .escrow_state
+                .try_borrow_mut_data()
+                .map_err(|_| EscrowError::AccountBorrowFailed)?,
+        )
+        .map_err(|_| EscrowError::InvalidEscrowState)?;
+        event.emit();
         Ok(())
     }
     fn process_exchange<'a>(
         accounts: &'a [AccountInfo<'a>],
         amount_expected_by_taker: u64,
+        fill_amount: u64,
+        min_amount_a: u64,
+        proof: Vec<[u8; 32]>,
     ) -> Result<(), EscrowError> {
-        let ctx = ExchangeAccount::unpack(accounts, amount_expected_by_taker)?;
+        let clock = Clock::get().map_err(|_| EscrowError::SysvarUnavailable)?;
+        let ctx = ExchangeAccount::unpack(
+            accounts,
+            amount_expected_by_taker,
+            fill_amount,
+            clock.unix_timestamp,
+        )?;
+        // a private escrow can only be taken by the taker chosen by the initializer
+        if !ctx.escrow_account_info.info.is_allowed_taker(ctx.taker.key) {
+            return Err(EscrowError::UnauthorizedTaker);
+        }
+        // a semi-private escrow can only be taken by takers proven to be in its allowlist
+        if !ctx
+            .escrow_account_info
+            .info
+            .is_listed_taker(ctx.taker.key, &proof)
+        {
+            return Err(EscrowError::InvalidTakerProof);
+        }
 
-        // create A token account owned by the taker
-        let create_a_reciever_ata = create_associated_token_account_idempotent(
-            &ctx.taker.key,
-            &ctx.taker.key,
-            &ctx.token_a_mint.key,
-            &spl_token::ID,
+        let amount_b = ctx
+            .escrow_account_info
+            .info
+            .price_for_fill(fill_amount)
+            .ok_or(EscrowError::ArithmeticOverflow)?;
+        if amount_b == 0 {
+            return Err(EscrowError::FillTooSmall);
+        }
+        let remaining_amount = ctx
+            .escrow_account_info
+            .info
+            .remaining_amount
+            .checked_sub(fill_amount)
+            .ok_or(EscrowError::ArithmeticOverflow)?;
+        // the last fill sweeps the whole vault so it can be closed
+        let amount_a = if remaining_amount == 0 {
+            ctx.token_a_vault.info.amount
+        } else {
+            fill_amount
+        };
+        let (fee_a, fee_b) = Self::protocol_fees(&ctx.config, amount_a, amount_b)?;
+        let taker_amount_a = amount_a
+            .checked_sub(fee_a)
+            .ok_or(EscrowError::ArithmeticOverflow)?;
+        // the minimum holds for what reaches the taker, after every fee taken from token A
+        let received_amount_a = ctx
+            .token_a_mint
+            .transfer_fee(clock.epoch, taker_amount_a)
+            .and_then(|fee| taker_amount_a.checked_sub(fee))
+            .ok_or(EscrowError::ArithmeticOverflow)?;
+        if received_amount_a < min_amount_a {
+            return Err(EscrowError::AmountABelowMinimum);
+        }
+
+        if !ctx.token_a_mint.is_native() && ctx.token_a_receiver.data_is_empty() {
+            // create A token account owned by the taker
+            let create_a_reciever_ata = create_associated_token_account_idempotent(
+                ctx.taker.key,
+                ctx.taker.key,
+                &ctx.token_a_mint.key,
+                &ctx.token_a_mint.program_id,
+            );
+            invoke(&create_a_reciever_ata, accounts).map_err(EscrowError::CpiFailed)?;
+        }
+        if !ctx.token_b_mint.is_native() {
+            // create B token account owned by the initializer
+            let creata_b_reciever_ata = create_associated_token_account_idempotent(
+                ctx.taker.key,
+                &ctx.escrow_account_info.info.initializer_pubkey,
+                &ctx.token_b_mint.key,
+                &ctx.token_b_mint.program_id,
+            );
+            invoke(&creata_b_reciever_ata, accounts).map_err(EscrowError::CpiFailed)?;
+        }
+        Self::create_fee_receiver(
+            accounts,
+            ctx.taker.key,
+            ctx.fee_recipient.key,
+            (fee_a, fee_b),
+            (&ctx.token_a_mint, &ctx.token_b_mint),
+        )?;
+        // transfer token B from taker to initializer
+        let initializer_amount_b = amount_b
+            .checked_sub(fee_b)
+            .ok_or(EscrowError::ArithmeticOverflow)?;
+        Self::transfer_b(
+            accounts,
+            &ctx,
+            ctx.token_b_receiver,
+            initializer_amount_b,
+            clock.epoch,
+        )?;
+        if fee_b > 0 {
+            Self::transfer_b(accounts, &ctx, ctx.fee_receiver, fee_b, clock.epoch)?;
+        }
+        // release token A once token B is paid
+        Self::transfer_a(accounts, &ctx, ctx.token_a_receiver, taker_amount_a)?;
+        if fee_a > 0 {
+            Self::transfer_a(accounts, &ctx, ctx.fee_receiver, fee_a)?;
+        }
+        Self::emit_exchanged(
+            &ctx.escrow_account_info,
+            ctx.taker.key,
+            taker_amount_a,
+            initializer_amount_b,
+            (fee_a, fee_b),
+            remaining_amount,
         );
-        invoke(&create_a_reciever_ata, accounts).unwrap();
 
-        // transfer out token A
-        let take_transfer = transfer_checked(
-            &spl_token::ID,
+        if remaining_amount > 0 {
+            // keep the escrow open for the next taker
+            let mut escrow_state = ctx.escrow_account_info.info;
+            escrow_state.remaining_amount = remaining_amount;
+            EscrowState::pack(
+                escrow_state,
+                &mut ctx
+                    .escrow_state
+                    .try_borrow_mut_data()
+                    .map_err(|_| EscrowError::AccountBorrowFailed)?,
+            )
+            .map_err(|_| EscrowError::InvalidEscrowState)?;
+            return Ok(());
+        }
+        Self::close_escrow(
+            accounts,
+            ctx.initializer,
+            ctx.escrow_state,
+            &ctx.escrow_account_info,
             &ctx.token_a_vault.key,
-            &ctx.token_a_mint.key,
-            ctx.token_a_receiver.key,
+            &ctx.token_a_mint,
+        )
+    }
+    /// Sends `amount` of token A from the vault, native SOL leaves the escrow account directly
+    fn transfer_a<'a>(
+        accounts: &'a [AccountInfo<'a>],
+        ctx: &ExchangeAccount<'a>,
+        to: &'a AccountInfo<'a>,
+        amount: u64,
+    ) -> Result<(), EscrowError> {
+        if ctx.token_a_mint.is_native() {
+            // the vault is the escrow account, owned by this program
+            return Self::move_lamports(ctx.escrow_state, to, amount);
+        }
+        Self::transfer_from_vault(
+            accounts,
+            &ctx.token_a_vault.key,
+            &ctx.token_a_mint,
             &ctx.escrow_account_info.key,
+            to.key,
+            amount,
+            &escrow_signer_seeds(&ctx.escrow_account_info.info).as_seeds(),
+        )
+    }
+    /// Sends token B from the taker so that `to` receives `net_amount`,
+    /// the taker covering the transfer fee of token B
+    fn transfer_b<'a>(
+        accounts: &'a [AccountInfo<'a>],
+        ctx: &ExchangeAccount<'a>,
+        to: &'a AccountInfo<'a>,
+        net_amount: u64,
+        epoch: u64,
+    ) -> Result<(), EscrowError> {
+        if ctx.token_b_mint.is_native() {
+            let transfer = system_instruction::transfer(ctx.taker.key, to.key, net_amount);
+            return invoke(&transfer, accounts).map_err(EscrowError::CpiFailed);
+        }
+        let gross_amount = Self::gross_amount(&ctx.token_b_mint, epoch, net_amount)?;
+        let transfer = transfer_checked(
+            &ctx.token_b_mint.program_id,
+            &ctx.token_b_founder.key,
+            &ctx.token_b_mint.key,
+            to.key,
+            ctx.taker.key,
+            &[],
+            gross_amount,
+            ctx.token_b_mint.info.decimals,
+        )
+        .map_err(EscrowError::CpiFailed)?;
+        invoke(&transfer, accounts).map_err(EscrowError::CpiFailed)
+    }
+    /// Creates the ATA of the fee recipient for the mint of the leg paying the protocol fee, if any,
+    /// a native fee being sent to the recipient wallet itself
+    fn create_fee_receiver(
+        accounts: &[AccountInfo],
+        payer: &Pubkey,
+        fee_recipient: &Pubkey,
+        (fee_a, fee_b): (u64, u64),
+        (token_a_mint, token_b_mint): (&MintAccount, &MintAccount),
+    ) -> Result<(), EscrowError> {
+        let fee_mint = match (fee_a, fee_b) {
+            (0, 0) => return Ok(()),
+            (0, _) => token_b_mint,
+            _ => token_a_mint,
+        };
+        if fee_mint.is_native() {
+            return Ok(());
+        }
+        let create_fee_receiver_ata = create_associated_token_account_idempotent(
+            payer,
+            fee_recipient,
+            &fee_mint.key,
+            &fee_mint.program_id,
+        );
+        invoke(&create_fee_receiver_ata, accounts).map_err(EscrowError::CpiFailed)
+    }
+    /// Returns the protocol fees of a trade, skimmed from the configured leg
+    fn protocol_fees(
+        config: &Option<Config>,
+        amount_a: u64,
+        amount_b: u64,
+    ) -> Result<(u64, u64), EscrowError> {
+        match config {
+            Some(config) if config.fee_side == FeeSide::A => Ok((
+                config
+                    .fee_for(amount_a)
+                    .ok_or(EscrowError::ArithmeticOverflow)?,
+                0,
+            )),
+            Some(config) => Ok((
+                0,
+                config
+                    .fee_for(amount_b)
+                    .ok_or(EscrowError::ArithmeticOverflow)?,
+            )),
+            None => Ok((0, 0)),
+        }
+    }
+    /// Returns the amount to send during `epoch` so that the receiver gets `net_amount` of the mint
+    fn gross_amount(mint: &MintAccount, epoch: u64, net_amount: u64) -> Result<u64, EscrowError> {
+        let gross_amount = mint
+            .pre_fee_amount(epoch, net_amount)
+            .ok_or(EscrowError::ArithmeticOverflow)?;
+        let received_amount = mint
+            .transfer_fee(epoch, gross_amount)
+            .and_then(|fee| gross_amount.checked_sub(fee))
+            .ok_or(EscrowError::ArithmeticOverflow)?;
+        if received_amount < net_amount {
+            return Err(EscrowError::TransferFeeTooHigh);
+        }
+        Ok(gross_amount)
+    }
+    /// Sends `amount` out of a vault owned by the PDA `authority` of this program
+    fn transfer_from_vault(
+        accounts: &[AccountInfo],
+        vault: &Pubkey,
+        mint: &MintAccount,
+        authority: &Pubkey,
+        to: &Pubkey,
+        amount: u64,
+        signer_seeds: &[&[u8]],
+    ) -> Result<(), EscrowError> {
+        let transfer = transfer_checked(
+            &mint.program_id,
+            vault,
+            &mint.key,
+            to,
+            authority,
             &[],
-            ctx.token_a_vault.info.amount,
-            ctx.token_a_mint.info.decimals,
+            amount,
+            mint.info.decimals,
         )
-        .unwrap();
+        .map_err(EscrowError::CpiFailed)?;
+        invoke_signed(&transfer, accounts, &[signer_seeds]).map_err(EscrowError::CpiFailed)
+    }
+    fn process_deposit<'a>(
+        accounts: &'a [AccountInfo<'a>],
+        amount_expected_by_counterparty: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<(), EscrowError> {
+        let clock = Clock::get().map_err(|_| EscrowError::SysvarUnavailable)?;
+        let ctx = DepositEscrowAccount::unpack(
+            accounts,
+            amount_expected_by_counterparty,
+            clock.unix_timestamp,
+        )?;
+        let escrow = &ctx.escrow_account_info.info;
+        // the counterparty takes the escrow, so it must be allowed to take it
+        if !escrow.is_allowed_taker(ctx.counterparty.key) {
+            return Err(EscrowError::UnauthorizedTaker);
+        }
+        if !escrow.is_listed_taker(ctx.counterparty.key, &proof) {
+            return Err(EscrowError::InvalidTakerProof);
+        }
+
+        // the deposit pays for everything left in the escrow
+        let amount_a = escrow.remaining_amount;
+        let amount_b = escrow
+            .price_for_fill(amount_a)
+            .ok_or(EscrowError::ArithmeticOverflow)?;
+        if amount_b == 0 {
+            return Err(EscrowError::FillTooSmall);
+        }
+        let deposit_state = DepositState {
+            is_initialized: true,
+            escrow: ctx.escrow_account_info.key,
+            counterparty: *ctx.counterparty.key,
+            mint_a: escrow.mint_a,
+            mint_b: escrow.mint_b,
+            amount_a,
+            amount_b,
+            bump: ctx.deposit_bump,
+        };
+        // create deposit account
+        let rent_info = Rent::get().map_err(|_| EscrowError::SysvarUnavailable)?;
+        let create_account_ix = system_instruction::create_account(
+            ctx.counterparty.key,
+            ctx.deposit_state.key,
+            rent_info.minimum_balance(DepositState::LEN),
+            DepositState::LEN
+                .try_into()
+                .map_err(|_| EscrowError::ArithmeticOverflow)?,
+            &id(),
+        );
         invoke_signed(
-            &take_transfer,
+            &create_account_ix,
             accounts,
-            &[&[
-                &ctx.escrow_account_info.info.seed.clone().to_le_bytes(),
-                &ctx.initializer.key.to_bytes(),
-                &ctx.escrow_account_info.info.bump.clone().to_le_bytes(),
-            ]],
+            &[&deposit_signer_seeds(&deposit_state).as_seeds()],
         )
-        .unwrap();
-
-        // create B token account owned by the initializer
-        let creata_b_reciever_ata = create_associated_token_account_idempotent(
-            &ctx.taker.key,
-            &ctx.escrow_account_info.key,
+        .map_err(EscrowError::CpiFailed)?;
+        // create vault account
+        let create_ata_ix = create_associated_token_account_idempotent(
+            ctx.counterparty.key,
+            ctx.deposit_state.key,
             &ctx.token_b_mint.key,
-            &spl_token::ID,
+            &ctx.token_b_mint.program_id,
         );
-        invoke(&creata_b_reciever_ata, accounts).unwrap();
+        invoke(&create_ata_ix, accounts).map_err(EscrowError::CpiFailed)?;
 
-        // transfer token B from taker to initializer
-        let transfer_b = transfer_checked(
-            &spl_token::ID,
+        // transfer token B to vault, the counterparty covering the transfer fee of both the
+        // deposit and the settlement paying it out, so the vault holds `amount_b` grossed up once
+        let vault_amount = Self::gross_amount(&ctx.token_b_mint, clock.epoch, amount_b)?;
+        let gross_amount = Self::gross_amount(&ctx.token_b_mint, clock.epoch, vault_amount)?;
+        let transfer = transfer_checked(
+            &ctx.token_b_mint.program_id,
             &ctx.token_b_founder.key,
             &ctx.token_b_mint.key,
-            ctx.token_b_receiver.key,
-            &ctx.taker.key,
+            ctx.token_b_vault.key,
+            ctx.counterparty.key,
             &[],
-            ctx.escrow_account_info.info.expected_amount,
+            gross_amount,
             ctx.token_b_mint.info.decimals,
         )
-        .unwrap();
-        invoke(&transfer_b, accounts).unwrap();
+        .map_err(EscrowError::CpiFailed)?;
+        invoke(&transfer, accounts).map_err(EscrowError::CpiFailed)?;
 
-        // close vault account return rent back to initializer
-        let close_vault = close_account(
-            &spl_token::ID,
+        let event = EscrowEvent::Deposited(EscrowDeposited {
+            escrow: deposit_state.escrow,
+            deposit: *ctx.deposit_state.key,
+            counterparty: deposit_state.counterparty,
+            mint_b: deposit_state.mint_b,
+            amount_a,
+            amount_b,
+        });
+        DepositState::pack(
+            deposit_state,
+            &mut ctx
+                .deposit_state
+                .try_borrow_mut_data()
+                .map_err(|_| EscrowError::AccountBorrowFailed)?,
+        )
+        .map_err(|_| EscrowError::InvalidDepositState)?;
+        event.emit();
+        Ok(())
+    }
+    fn process_settle<'a>(accounts: &'a [AccountInfo<'a>]) -> Result<(), EscrowError> {
+        let clock = Clock::get().map_err(|_| EscrowError::SysvarUnavailable)?;
+        let ctx = SettleEscrowAccount::unpack(accounts, clock.unix_timestamp)?;
+        let escrow = &ctx.escrow_account_info;
+        let deposit = &ctx.deposit_account_info;
+
+        // both vaults are swept so they can be closed
+        let amount_a = ctx.token_a_vault.info.amount;
+        let amount_b = deposit.info.amount_b;
+        let (fee_a, fee_b) = Self::protocol_fees(&ctx.config, amount_a, amount_b)?;
+        let escrow_seeds = escrow_signer_seeds(&escrow.info);
+        let deposit_seeds = deposit_signer_seeds(&deposit.info);
+
+        if ctx.token_a_receiver.data_is_empty() {
+            // create A token account owned by the counterparty
+            let create_a_reciever_ata = create_associated_token_account_idempotent(
+                ctx.settler.key,
+                ctx.counterparty.key,
+                &ctx.token_a_mint.key,
+                &ctx.token_a_mint.program_id,
+            );
+            invoke(&create_a_reciever_ata, accounts).map_err(EscrowError::CpiFailed)?;
+        }
+        // create B token account owned by the initializer
+        let create_b_reciever_ata = create_associated_token_account_idempotent(
+            ctx.settler.key,
+            ctx.initializer.key,
+            &ctx.token_b_mint.key,
+            &ctx.token_b_mint.program_id,
+        );
+        invoke(&create_b_reciever_ata, accounts).map_err(EscrowError::CpiFailed)?;
+        Self::create_fee_receiver(
+            accounts,
+            ctx.settler.key,
+            ctx.fee_recipient.key,
+            (fee_a, fee_b),
+            (&ctx.token_a_mint, &ctx.token_b_mint),
+        )?;
+
+        // swap both vaults, the deposit vault holding the transfer fee of paying the initializer
+        // on top of the price so the initializer receives its share net of it
+        let initializer_amount_b = amount_b
+            .checked_sub(fee_b)
+            .ok_or(EscrowError::ArithmeticOverflow)?;
+        let counterparty_amount_a = amount_a
+            .checked_sub(fee_a)
+            .ok_or(EscrowError::ArithmeticOverflow)?;
+        let initializer_gross_b =
+            Self::gross_amount(&ctx.token_b_mint, clock.epoch, initializer_amount_b)?;
+        // a transfer fee raised since the deposit can leave the vault short
+        let leftover_b = ctx
+            .token_b_vault
+            .info
+            .amount
+            .checked_sub(initializer_gross_b)
+            .ok_or(EscrowError::TransferFeeTooHigh)?;
+        // the fee receiver gets what is left once the initializer is paid, without a protocol fee
+        // the initializer sweeps the vault
+        let (initializer_gross_b, fee_gross_b) = match fee_b {
+            0 => (ctx.token_b_vault.info.amount, 0),
+            _ => (initializer_gross_b, leftover_b),
+        };
+        Self::transfer_from_vault(
+            accounts,
+            &ctx.token_b_vault.key,
+            &ctx.token_b_mint,
+            &deposit.key,
+            ctx.token_b_receiver.key,
+            initializer_gross_b,
+            &deposit_seeds.as_seeds(),
+        )?;
+        if fee_gross_b > 0 {
+            Self::transfer_from_vault(
+                accounts,
+                &ctx.token_b_vault.key,
+                &ctx.token_b_mint,
+                &deposit.key,
+                ctx.fee_receiver.key,
+                fee_gross_b,
+                &deposit_seeds.as_seeds(),
+            )?;
+        }
+        Self::transfer_from_vault(
+            accounts,
             &ctx.token_a_vault.key,
-            &ctx.escrow_account_info.key,
-            &ctx.escrow_account_info.key,
+            &ctx.token_a_mint,
+            &escrow.key,
+            ctx.token_a_receiver.key,
+            counterparty_amount_a,
+            &escrow_seeds.as_seeds(),
+        )?;
+        if fee_a > 0 {
+            Self::transfer_from_vault(
+                accounts,
+                &ctx.token_a_vault.key,
+                &ctx.token_a_mint,
+                &escrow.key,
+                ctx.fee_receiver.key,
+                fee_a,
+                &escrow_seeds.as_seeds(),
+            )?;
+        }
+        Self::emit_exchanged(
+            escrow,
+            ctx.counterparty.key,
+            counterparty_amount_a,
+            initializer_amount_b,
+            (fee_a, fee_b),
+            0,
+        );
+
+        // close both vaults before moving the rent of the state accounts
+        Self::close_vault(
+            accounts,
+            &ctx.token_a_vault.key,
+            &ctx.token_a_mint,
+            &escrow.key,
+            &escrow_seeds.as_seeds(),
+        )?;
+        Self::close_vault(
+            accounts,
+            &ctx.token_b_vault.key,
+            &ctx.token_b_mint,
+            &deposit.key,
+            &deposit_seeds.as_seeds(),
+        )?;
+        Self::close_program_account(ctx.escrow_state, ctx.initializer)?;
+        Self::close_program_account(ctx.deposit_state, ctx.counterparty)
+    }
+    fn process_withdraw_deposit<'a>(accounts: &'a [AccountInfo<'a>]) -> Result<(), EscrowError> {
+        let ctx = WithdrawDepositAccount::unpack(accounts)?;
+        let deposit = &ctx.deposit_account_info;
+        let deposit_seeds = deposit_signer_seeds(&deposit.info);
+
+        // refund token B back to the counterparty
+        let refund_amount = ctx.token_b_vault.info.amount;
+        if refund_amount > 0 {
+            Self::transfer_from_vault(
+                accounts,
+                &ctx.token_b_vault.key,
+                &ctx.token_b_mint,
+                &deposit.key,
+                &ctx.token_b_receiver.key,
+                refund_amount,
+                &deposit_seeds.as_seeds(),
+            )?;
+        }
+        EscrowEvent::DepositWithdrawn(DepositWithdrawn {
+            escrow: deposit.info.escrow,
+            deposit: deposit.key,
+            counterparty: deposit.info.counterparty,
+            mint_b: deposit.info.mint_b,
+            refund_amount,
+        })
+        .emit();
+
+        Self::close_vault(
+            accounts,
+            &ctx.token_b_vault.key,
+            &ctx.token_b_mint,
+            &deposit.key,
+            &deposit_seeds.as_seeds(),
+        )?;
+        Self::close_program_account(ctx.deposit_state, ctx.counterparty)
+    }
+    fn process_cancel<'a>(accounts: &'a [AccountInfo<'a>]) -> Result<(), EscrowError> {
+        let ctx = CancelEscrowAccount::unpack(accounts)?;
+
+        Self::refund_escrow(
+            accounts,
+            ctx.initializer,
+            ctx.escrow_state,
+            &ctx.escrow_account_info,
+            &ctx.token_a_vault,
+            ctx.token_a_receiver.key,
+            &ctx.token_a_mint,
+        )?;
+        Self::emit_cancelled(&ctx.escrow_account_info, &ctx.token_a_vault, false);
+        Ok(())
+    }
+    fn process_expire<'a>(accounts: &'a [AccountInfo<'a>]) -> Result<(), EscrowError> {
+        let now = Clock::get()
+            .map_err(|_| EscrowError::SysvarUnavailable)?
+            .unix_timestamp;
+        let ctx = ExpireEscrowAccount::unpack(accounts, now)?;
+
+        if !ctx.token_a_mint.is_native() {
+            // create A token account owned by the initializer
+            let create_a_reciever_ata = create_associated_token_account_idempotent(
+                ctx.payer.key,
+                ctx.initializer.key,
+                &ctx.token_a_mint.key,
+                &ctx.token_a_mint.program_id,
+            );
+            invoke(&create_a_reciever_ata, accounts).map_err(EscrowError::CpiFailed)?;
+        }
+
+        Self::refund_escrow(
+            accounts,
+            ctx.initializer,
+            ctx.escrow_state,
+            &ctx.escrow_account_info,
+            &ctx.token_a_vault,
+            ctx.token_a_receiver.key,
+            &ctx.token_a_mint,
+        )?;
+        Self::emit_cancelled(&ctx.escrow_account_info, &ctx.token_a_vault, true);
+        Ok(())
+    }
+    fn emit_exchanged(
+        escrow_account_info: &EscrowAccount,
+        taker: &Pubkey,
+        amount_a: u64,
+        amount_b: u64,
+        (fee_a, fee_b): (u64, u64),
+        remaining_amount: u64,
+    ) {
+        EscrowEvent::Exchanged(EscrowExchanged {
+            escrow: escrow_account_info.key,
+            initializer: escrow_account_info.info.initializer_pubkey,
+            taker: *taker,
+            mint_a: escrow_account_info.info.mint_a,
+            mint_b: escrow_account_info.info.mint_b,
+            amount_a,
+            amount_b,
+            // only one leg pays the protocol fee
+            fee_amount: fee_a.max(fee_b),
+            remaining_amount,
+            seed: escrow_account_info.info.seed,
+        })
+        .emit();
+    }
+    fn emit_cancelled(
+        escrow_account_info: &EscrowAccount,
+        token_a_vault: &TokenAccount,
+        expired: bool,
+    ) {
+        EscrowEvent::Cancelled(EscrowCancelled {
+            escrow: escrow_account_info.key,
+            initializer: escrow_account_info.info.initializer_pubkey,
+            mint_a: escrow_account_info.info.mint_a,
+            refund_amount: token_a_vault.info.amount,
+            seed: escrow_account_info.info.seed,
+            expired,
+        })
+        .emit();
+    }
+    fn process_migrate_escrow<'a>(accounts: &'a [AccountInfo<'a>]) -> Result<(), EscrowError> {
+        let ctx = MigrateEscrowAccount::unpack(accounts)?;
+
+        // top up the rent of the extra space, keeping any native SOL deposit untouched
+        let rent_info = Rent::get().map_err(|_| EscrowError::SysvarUnavailable)?;
+        let top_up = rent_info
+            .minimum_balance(EscrowState::LEN)
+            .saturating_sub(rent_info.minimum_balance(ctx.escrow_state.data_len()));
+        if top_up > 0 {
+            let transfer =
+                system_instruction::transfer(ctx.initializer.key, ctx.escrow_state.key, top_up);
+            invoke(&transfer, accounts).map_err(EscrowError::CpiFailed)?;
+        }
+        ctx.escrow_state
+            .realloc(EscrowState::LEN, true)
+            .map_err(|_| EscrowError::ReallocFailed)?;
+
+        // the v1 layout did not record the deposit, the whole vault balance is left to fill
+        let mut state = ctx.escrow_account_info.info;
+        if ctx.escrow_account_info.version == 1 {
+            state.deposit_amount = ctx.token_a_vault.info.amount;
+            state.remaining_amount = ctx.token_a_vault.info.amount;
+        }
+
+        // rewrite the state in the current layout
+        EscrowState::pack(
+            state,
+            &mut ctx
+                .escrow_state
+                .try_borrow_mut_data()
+                .map_err(|_| EscrowError::AccountBorrowFailed)?,
+        )
+        .map_err(|_| EscrowError::InvalidEscrowState)?;
+        Ok(())
+    }
+    fn process_init_config<'a>(
+        accounts: &'a [AccountInfo<'a>],
+        fee_bps: u16,
+        fee_side: FeeSide,
+    ) -> Result<(), EscrowError> {
+        let ctx = InitConfigAccount::unpack(accounts, fee_bps)?;
+
+        let config = Config {
+            admin: *ctx.admin.key,
+            fee_recipient: *ctx.fee_recipient.key,
+            fee_bps,
+            fee_side,
+            bump: ctx.config_bump,
+            paused: false,
+            pending_admin: None,
+        };
+        // create config account
+        let rent_info = Rent::get().map_err(|_| EscrowError::SysvarUnavailable)?;
+        let create_account_ix = system_instruction::create_account(
+            ctx.admin.key,
+            ctx.config.key,
+            rent_info.minimum_balance(Config::LEN),
+            Config::LEN
+                .try_into()
+                .map_err(|_| EscrowError::ArithmeticOverflow)?,
+            &id(),
+        );
+        invoke_signed(
+            &create_account_ix,
+            accounts,
+            &[&[CONFIG_SEED, &[ctx.config_bump]]],
+        )
+        .map_err(EscrowError::CpiFailed)?;
+
+        Config::pack(
+            config,
+            &mut ctx
+                .config
+                .try_borrow_mut_data()
+                .map_err(|_| EscrowError::AccountBorrowFailed)?,
+        )
+        .map_err(|_| EscrowError::InvalidConfigState)?;
+        Ok(())
+    }
+    fn process_update_config<'a>(
+        accounts: &'a [AccountInfo<'a>],
+        fee_bps: u16,
+        fee_side: FeeSide,
+    ) -> Result<(), EscrowError> {
+        let ctx = UpdateConfigAccount::unpack(accounts, fee_bps)?;
+
+        let mut config = ctx.config_account_info.info;
+        config.fee_recipient = *ctx.fee_recipient.key;
+        config.fee_bps = fee_bps;
+        config.fee_side = fee_side;
+        Self::write_config(ctx.config, config)
+    }
+    fn process_set_paused<'a>(
+        accounts: &'a [AccountInfo<'a>],
+        paused: bool,
+    ) -> Result<(), EscrowError> {
+        let ctx = SetPausedAccount::unpack(accounts)?;
+
+        let mut config = ctx.config_account_info.info;
+        config.paused = paused;
+        Self::write_config(ctx.config, config)
+    }
+    fn process_transfer_admin<'a>(accounts: &'a [AccountInfo<'a>]) -> Result<(), EscrowError> {
+        let ctx = TransferAdminAccount::unpack(accounts)?;
+
+        // the handover completes once the new admin accepts it
+        let mut config = ctx.config_account_info.info;
+        config.pending_admin = Some(*ctx.new_admin.key);
+        Self::write_config(ctx.config, config)
+    }
+    fn process_accept_admin<'a>(accounts: &'a [AccountInfo<'a>]) -> Result<(), EscrowError> {
+        let ctx = AcceptAdminAccount::unpack(accounts)?;
+
+        let mut config = ctx.config_account_info.info;
+        config.admin = *ctx.new_admin.key;
+        config.pending_admin = None;
+        Self::write_config(ctx.config, config)
+    }
+    /// Packs `config` back into the config account
+    fn write_config(info: &AccountInfo, config: Config) -> Result<(), EscrowError> {
+        Config::pack(
+            config,
+            &mut info
+                .try_borrow_mut_data()
+                .map_err(|_| EscrowError::AccountBorrowFailed)?,
+        )
+        .map_err(|_| EscrowError::InvalidConfigState)
+    }
+    /// Sends the whole vault balance back to the initializer, then closes the escrow
+    fn refund_escrow<'a>(
+        accounts: &'a [AccountInfo<'a>],
+        initializer: &'a AccountInfo<'a>,
+        escrow_state: &'a AccountInfo<'a>,
+        escrow_account_info: &EscrowAccount,
+        token_a_vault: &TokenAccount,
+        token_a_receiver: &Pubkey,
+        token_a_mint: &MintAccount,
+    ) -> Result<(), EscrowError> {
+        if token_a_mint.is_native() {
+            // the deposit sits in the escrow account, closing it refunds the initializer
+            return Self::close_escrow(
+                accounts,
+                initializer,
+                escrow_state,
+                escrow_account_info,
+                &token_a_vault.key,
+                token_a_mint,
+            );
+        }
+        // refund token A back to initializer
+        let refund_transfer = transfer_checked(
+            &token_a_mint.program_id,
+            &token_a_vault.key,
+            &token_a_mint.key,
+            token_a_receiver,
+            &escrow_account_info.key,
             &[],
+            token_a_vault.info.amount,
+            token_a_mint.info.decimals,
         )
-        .unwrap();
+        .map_err(EscrowError::CpiFailed)?;
         invoke_signed(
-            &close_vault,
-            accounts,
-            &[&[
-                &ctx.escrow_account_info.info.seed.clone().to_le_bytes(),
-                &ctx.initializer.key.to_bytes(),
-                &ctx.escrow_account_info.info.bump.clone().to_le_bytes(),
-            ]],
-        )
-        .unwrap();
-        // transfer the rent inside escrow account back to initializer
-        **ctx.initializer.try_borrow_mut_lamports().unwrap() = ctx
-            .initializer
-            .lamports()
-            .checked_add(ctx.escrow_state.lamports())
-            .unwrap();
-        **ctx.escrow_state.try_borrow_mut_lamports().unwrap() = 0;
-        // clear escrow account
-        EscrowState::pack(
-            EscrowState::default(),
-            &mut ctx.escrow_state.try_borrow_mut_data().unwrap(),
+            &refund_transfer,
+            accounts,
+            &[&escrow_signer_seeds(&escrow_account_info.info).as_seeds()],
         )
-        .unwrap();
+        .map_err(EscrowError::CpiFailed)?;
+
+        Self::close_escrow(
+            accounts,
+            initializer,
+            escrow_state,
+            escrow_account_info,
+            &token_a_vault.key,
+            token_a_mint,
+        )
+    }
+    /// Closes the (empty) vault and the escrow state, returning all rent to the initializer
+    fn close_escrow<'a>(
+        accounts: &'a [AccountInfo<'a>],
+        initializer: &'a AccountInfo<'a>,
+        escrow_state: &'a AccountInfo<'a>,
+        escrow_account_info: &EscrowAccount,
+        token_a_vault: &Pubkey,
+        token_a_mint: &MintAccount,
+    ) -> Result<(), EscrowError> {
+        if token_a_mint.is_native() {
+            return Self::close_program_account(escrow_state, initializer);
+        }
+        // close vault account return rent back to initializer
+        Self::close_vault(
+            accounts,
+            token_a_vault,
+            token_a_mint,
+            &escrow_account_info.key,
+            &escrow_signer_seeds(&escrow_account_info.info).as_seeds(),
+        )?;
+        Self::close_program_account(escrow_state, initializer)
+    }
+    /// Closes an empty vault owned by the PDA `authority` of this program, its rent going to the PDA
+    fn close_vault(
+        accounts: &[AccountInfo],
+        vault: &Pubkey,
+        mint: &MintAccount,
+        authority: &Pubkey,
+        signer_seeds: &[&[u8]],
+    ) -> Result<(), EscrowError> {
+        // withheld transfer fees block closing a token-2022 account
+        if mint.transfer_fee_config.is_some() {
+            let harvest = harvest_withheld_tokens_to_mint(&mint.program_id, &mint.key, &[vault])
+                .map_err(EscrowError::CpiFailed)?;
+            invoke(&harvest, accounts).map_err(EscrowError::CpiFailed)?;
+        }
+        let close_vault = close_account(&mint.program_id, vault, authority, authority, &[])
+            .map_err(EscrowError::CpiFailed)?;
+        invoke_signed(&close_vault, accounts, &[signer_seeds]).map_err(EscrowError::CpiFailed)
+    }
+    /// Closes an account owned by this program: every lamport goes to `destination`, the data
+    /// is truncated to nothing and the account is handed back to the system program, so lamports
+    /// sent to it later in the same transaction leave an empty system account rather than reviving it
+    fn close_program_account<'a>(
+        info: &'a AccountInfo<'a>,
+        destination: &'a AccountInfo<'a>,
+    ) -> Result<(), EscrowError> {
+        Self::move_lamports(info, destination, info.lamports())?;
+        info.realloc(0, false)
+            .map_err(|_| EscrowError::ReallocFailed)?;
+        info.assign(&system_program::ID);
+        Ok(())
+    }
+    /// Moves lamports out of an account owned by this program
+    fn move_lamports<'a>(
+        from: &'a AccountInfo<'a>,
+        to: &'a AccountInfo<'a>,
+        amount: u64,
+    ) -> Result<(), EscrowError> {
+        let from_lamports = from
+            .lamports()
+            .checked_sub(amount)
+            .ok_or(EscrowError::ArithmeticOverflow)?;
+        let to_lamports = to
+            .lamports()
+            .checked_add(amount)
+            .ok_or(EscrowError::ArithmeticOverflow)?;
+        **from
+            .try_borrow_mut_lamports()
+            .map_err(|_| EscrowError::AccountBorrowFailed)? = from_lamports;
+        **to.try_borrow_mut_lamports()
+            .map_err(|_| EscrowError::AccountBorrowFailed)? = to_lamports;
         Ok(())
     }
 }
@@ -1,15 +1,50 @@
 use solana_program::{
     account_info::AccountInfo,
     program_error::ProgramError,
+    program_option::COption,
     program_pack::{IsInitialized, Pack, Sealed},
     pubkey::Pubkey,
+    system_program,
 };
 
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use borsh::{BorshDeserialize, BorshSerialize};
+use shank::ShankAccount;
 
-use crate::error::EscrowError;
-use spl_token::state::{Account, Mint};
-#[derive(Default)]
+use crate::{error::EscrowError, merkle::verify_taker_proof};
+use spl_token::native_mint;
+use spl_token_2022::{
+    extension::{transfer_fee::TransferFeeConfig, BaseStateWithExtensions, StateWithExtensions},
+    state::{Account, AccountState, Mint},
+};
+
+/// Which side of the trade, if any, is native SOL held as plain lamports
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum NativeSide {
+    #[default]
+    None,
+    A,
+    B,
+}
+impl NativeSide {
+    pub fn from_u8(value: u8) -> Option<NativeSide> {
+        match value {
+            0 => Some(NativeSide::None),
+            1 => Some(NativeSide::A),
+            2 => Some(NativeSide::B),
+            _ => None,
+        }
+    }
+    pub fn is_native_a(&self) -> bool {
+        *self == NativeSide::A
+    }
+    pub fn is_native_b(&self) -> bool {
+        *self == NativeSide::B
+    }
+}
+
+/// Escrow account, the borsh-encoded state follows the discriminator and the layout version
+#[derive(Clone, Default, BorshSerialize, BorshDeserialize, ShankAccount)]
 pub struct EscrowState {
     pub is_initialized: bool,
     pub initializer_pubkey: Pubkey,
@@ -18,6 +53,58 @@ pub struct EscrowState {
     pub expected_amount: u64,
     pub bump: u8,
     pub seed: u64,
+    pub expiry: Option<i64>,
+    pub deposit_amount: u64,
+    pub remaining_amount: u64,
+    pub native_side: NativeSide,
+    pub allowed_taker: Option<Pubkey>,
+    pub taker_merkle_root: Option<[u8; 32]>,
+}
+
+impl EscrowState {
+    /// Returns true once the unix timestamp `now` has reached the expiry of the escrow
+    pub fn is_expired(&self, now: i64) -> bool {
+        match self.expiry {
+            Some(expiry) => now >= expiry,
+            None => false,
+        }
+    }
+    /// Returns true if `taker` may take the escrow, a private escrow only admits its allowed taker
+    pub fn is_allowed_taker(&self, taker: &Pubkey) -> bool {
+        match self.allowed_taker {
+            Some(allowed_taker) => allowed_taker.eq(taker),
+            None => true,
+        }
+    }
+    /// Returns true if `proof` shows that `taker` belongs to the allowlist of the escrow,
+    /// an escrow without allowlist admits anyone
+    pub fn is_listed_taker(&self, taker: &Pubkey, proof: &[[u8; 32]]) -> bool {
+        match &self.taker_merkle_root {
+            Some(root) => verify_taker_proof(proof, root, taker),
+            None => true,
+        }
+    }
+    /// Returns the amount of token B owed for buying `fill_amount` of the remaining token A.
+    ///
+    /// The price is taken from the cumulative amount filled so far, rounded down, so the sum over
+    /// all fills is exactly `expected_amount` however the deposit is split. A fill too small to
+    /// move the rounded total is priced at zero, the final fill always pays what is left.
+    pub fn price_for_fill(&self, fill_amount: u64) -> Option<u64> {
+        let filled_before = self.deposit_amount.checked_sub(self.remaining_amount)?;
+        let filled_after = filled_before.checked_add(fill_amount)?;
+        let owed_before = self.cumulative_price(filled_before)?;
+        let owed_after = self.cumulative_price(filled_after)?;
+        owed_after.checked_sub(owed_before)
+    }
+    fn cumulative_price(&self, filled: u64) -> Option<u64> {
+        let numerator = (filled as u128).checked_mul(self.expected_amount as u128)?;
+        let denominator = self.deposit_amount as u128;
+        if denominator == 0 {
+            return None;
+        }
+        let price = numerator.checked_div(denominator)?;
+        price.try_into().ok()
+    }
 }
 
 impl Sealed for EscrowState {}
@@ -28,10 +115,48 @@ impl IsInitialized for EscrowState {
     }
 }
 
-impl Pack for EscrowState {
-    const LEN: usize = 114;
-    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
-        let src = array_ref![src, 0, EscrowState::LEN];
+/// A program-owned account type, its data opens with the type's 8-byte discriminator
+/// so one account type can never be passed as another
+pub trait Discriminator {
+    /// The first 8 bytes of sha256("account:<TypeName>")
+    const DISCRIMINATOR: [u8; 8];
+}
+
+/// Checks that `data` opens with the discriminator of `T`
+pub fn check_discriminator<T: Discriminator>(data: &[u8]) -> Result<(), EscrowError> {
+    match data.get(..8) {
+        Some(discriminator) if discriminator.eq(&T::DISCRIMINATOR) => Ok(()),
+        _ => Err(EscrowError::InvalidAccountType),
+    }
+}
+
+impl Discriminator for EscrowState {
+    const DISCRIMINATOR: [u8; 8] = [19, 90, 148, 111, 55, 130, 229, 108];
+}
+/// Layout version written by this program
+pub const ESCROW_STATE_VERSION: u8 = 2;
+
+impl EscrowState {
+    /// Length of the headerless v1 layout, which only stored the fields up to the seed
+    pub const V1_LEN: usize = 114;
+    /// Length of the body following the header, the longest borsh encoding of the state
+    pub const BODY_LEN: usize = 206;
+    /// Space kept free at the end of the versioned layout for future fields
+    pub const RESERVED_LEN: usize = 64;
+
+    /// Unpacks an escrow state of any layout version, returning it along with its version
+    pub fn unpack_versioned(src: &[u8]) -> Result<(EscrowState, u8), ProgramError> {
+        if src.len() == EscrowState::V1_LEN {
+            let state = EscrowState::unpack_v1(array_ref![src, 0, EscrowState::V1_LEN])?;
+            return Ok((state, 1));
+        }
+        let state = EscrowState::unpack_unchecked(src)?;
+        Ok((state, ESCROW_STATE_VERSION))
+    }
+
+    /// Unpacks the v1 layout, which did not record the deposit: the amounts are left at zero
+    /// for the migration to fill them from the vault balance
+    fn unpack_v1(src: &[u8; EscrowState::V1_LEN]) -> Result<EscrowState, ProgramError> {
         let (is_initialized, initializer_pubkey, mint_a, mint_b, expected_amount, bump, seed) =
             array_refs![src, 1, 32, 32, 32, 8, 1, 8];
         let is_initialized = match is_initialized {
@@ -48,38 +173,159 @@ impl Pack for EscrowState {
             expected_amount: u64::from_le_bytes(*expected_amount),
             bump: bump[0],
             seed: u64::from_le_bytes(*seed),
+            ..EscrowState::default()
         })
     }
+}
+
+impl Pack for EscrowState {
+    const LEN: usize = 8 + 1 + EscrowState::BODY_LEN + EscrowState::RESERVED_LEN;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src: &[u8; EscrowState::LEN] = src
+            .try_into()
+            .map_err(|_| EscrowError::InvalidEscrowState.print_into())?;
+        let (discriminator, version, body, _reserved) =
+            array_refs![src, 8, 1, EscrowState::BODY_LEN, EscrowState::RESERVED_LEN];
+        if !discriminator.eq(&EscrowState::DISCRIMINATOR) || version[0] != ESCROW_STATE_VERSION {
+            return Err(EscrowError::InvalidEscrowState.print_into());
+        }
+        EscrowState::deserialize(&mut &body[..])
+            .map_err(|_| EscrowError::InvalidEscrowState.print_into())
+    }
 
     fn pack_into_slice(&self, dst: &mut [u8]) {
         let dst = array_mut_ref![dst, 0, EscrowState::LEN];
-        let (
-            is_initialized_dst,
-            initializer_pubkey_dst,
-            mint_a_dst,
-            mint_b_dst,
-            expected_amount_dst,
-            bump_dst,
-            seed_dst,
-        ) = mut_array_refs![dst, 1, 32, 32, 32, 8, 1, 8];
-
-        let EscrowState {
-            is_initialized,
-            initializer_pubkey,
-            mint_a,
-            mint_b,
-            expected_amount,
-            bump,
-            seed,
-        } = self;
+        let (discriminator_dst, version_dst, body_dst, reserved_dst) =
+            mut_array_refs![dst, 8, 1, EscrowState::BODY_LEN, EscrowState::RESERVED_LEN];
+        *discriminator_dst = EscrowState::DISCRIMINATOR;
+        version_dst[0] = ESCROW_STATE_VERSION;
+        // the borsh encoding is shorter than the body when options are absent, the tail stays zeroed
+        *body_dst = [0; EscrowState::BODY_LEN];
+        self.serialize(&mut &mut body_dst[..])
+            .expect("the borsh encoding of the escrow state fits its body");
+        *reserved_dst = [0; EscrowState::RESERVED_LEN];
+    }
+}
+
+/// Which leg of an exchange the protocol fee is taken from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum FeeSide {
+    #[default]
+    B,
+    A,
+}
 
-        is_initialized_dst[0] = *is_initialized as u8;
-        initializer_pubkey_dst.copy_from_slice(initializer_pubkey.as_ref());
-        mint_a_dst.copy_from_slice(mint_a.as_ref());
-        mint_b_dst.copy_from_slice(mint_b.as_ref());
-        *expected_amount_dst = expected_amount.to_le_bytes();
-        *bump_dst = bump.to_le_bytes();
-        *seed_dst = seed.to_le_bytes()
+/// Basis points in a whole
+pub const BASIS_POINTS: u16 = 10_000;
+
+/// Largest protocol fee, 10%
+pub const MAX_FEE_BPS: u16 = 1_000;
+
+/// Global settings of the program, stored at the config PDA
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize, ShankAccount,
+)]
+pub struct Config {
+    pub admin: Pubkey,
+    pub fee_recipient: Pubkey,
+    pub fee_bps: u16,
+    pub fee_side: FeeSide,
+    pub bump: u8,
+    pub paused: bool,
+    pub pending_admin: Option<Pubkey>,
+}
+impl Config {
+    /// Length of the space kept free at the end of the config for future fields
+    pub const RESERVED_LEN: usize = 30;
+
+    /// Returns the protocol fee owed on `amount`, rounded down in favour of the traders
+    pub fn fee_for(&self, amount: u64) -> Option<u64> {
+        let fee = (amount as u128)
+            .checked_mul(self.fee_bps as u128)?
+            .checked_div(BASIS_POINTS as u128)?;
+        fee.try_into().ok()
+    }
+}
+impl Discriminator for Config {
+    const DISCRIMINATOR: [u8; 8] = [155, 12, 170, 224, 30, 250, 204, 130];
+}
+impl Sealed for Config {}
+impl Pack for Config {
+    const LEN: usize = 8 + 32 + 32 + 2 + 1 + 1 + 1 + 1 + 32 + Config::RESERVED_LEN;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src: &[u8; Config::LEN] = src
+            .try_into()
+            .map_err(|_| EscrowError::InvalidConfigState.print_into())?;
+        let (discriminator, body) = array_refs![src, 8, Config::LEN - 8];
+        if !discriminator.eq(&Config::DISCRIMINATOR) {
+            return Err(EscrowError::InvalidAccountType.print_into());
+        }
+        Config::deserialize(&mut &body[..])
+            .map_err(|_| EscrowError::InvalidConfigState.print_into())
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, Config::LEN];
+        let (discriminator_dst, body_dst) = mut_array_refs![dst, 8, Config::LEN - 8];
+        *discriminator_dst = Config::DISCRIMINATOR;
+        *body_dst = [0; Config::LEN - 8];
+        self.serialize(&mut &mut body_dst[..])
+            .expect("the borsh encoding of the config fits its body");
+    }
+}
+
+/// Token B deposited by a counterparty against an escrow ahead of settlement, stored at the
+/// deposit PDA of the escrow, the vault being the token B ATA of the deposit state
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize, ShankAccount,
+)]
+pub struct DepositState {
+    pub is_initialized: bool,
+    pub escrow: Pubkey,
+    pub counterparty: Pubkey,
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    /// amount of token A bought, the remaining amount of the escrow when depositing
+    pub amount_a: u64,
+    /// amount of token B paid for `amount_a`, held by the vault along with the transfer fee
+    /// of paying it out on settlement
+    pub amount_b: u64,
+    pub bump: u8,
+}
+impl DepositState {
+    /// Length of the space kept free at the end of the deposit for future fields
+    pub const RESERVED_LEN: usize = 32;
+}
+impl Discriminator for DepositState {
+    const DISCRIMINATOR: [u8; 8] = [203, 5, 16, 65, 63, 206, 55, 194];
+}
+impl Sealed for DepositState {}
+impl IsInitialized for DepositState {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+impl Pack for DepositState {
+    const LEN: usize = 8 + 1 + 32 + 32 + 32 + 32 + 8 + 8 + 1 + DepositState::RESERVED_LEN;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src: &[u8; DepositState::LEN] = src
+            .try_into()
+            .map_err(|_| EscrowError::InvalidDepositState.print_into())?;
+        let (discriminator, body) = array_refs![src, 8, DepositState::LEN - 8];
+        if !discriminator.eq(&DepositState::DISCRIMINATOR) {
+            return Err(EscrowError::InvalidAccountType.print_into());
+        }
+        DepositState::deserialize(&mut &body[..])
+            .map_err(|_| EscrowError::InvalidDepositState.print_into())
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, DepositState::LEN];
+        let (discriminator_dst, body_dst) = mut_array_refs![dst, 8, DepositState::LEN - 8];
+        *discriminator_dst = DepositState::DISCRIMINATOR;
+        *body_dst = [0; DepositState::LEN - 8];
+        self.serialize(&mut &mut body_dst[..])
+            .expect("the borsh encoding of the deposit fits its body");
     }
 }
 
@@ -93,28 +339,113 @@ impl TokenAccount {
     pub fn unpack(info: &AccountInfo) -> Result<TokenAccount, EscrowError> {
         Ok(TokenAccount {
             key: *info.key,
-            info: match Account::unpack(&info.try_borrow_data().unwrap()) {
-                Ok(s) => s,
+            info: match StateWithExtensions::<Account>::unpack(
+                &info
+                    .try_borrow_data()
+                    .map_err(|_| EscrowError::AccountBorrowFailed)?,
+            ) {
+                Ok(s) => s.base,
                 Err(_) => return Err(EscrowError::DeserializeTokenAccountError),
             },
             program_id: *info.owner,
             is_writable: info.is_writable,
         })
     }
+    /// Views a wallet (or the escrow state) holding native SOL as a token account,
+    /// its amount being the lamports above `reserved`
+    pub fn native(info: &AccountInfo, reserved: u64) -> TokenAccount {
+        TokenAccount {
+            key: *info.key,
+            info: Account {
+                mint: native_mint::ID,
+                owner: *info.key,
+                amount: info.lamports().saturating_sub(reserved),
+                state: AccountState::Initialized,
+                is_native: COption::Some(reserved),
+                ..Account::default()
+            },
+            program_id: system_program::ID,
+            is_writable: info.is_writable,
+        }
+    }
 }
+// an escrow is read without its discriminator at the legacy v1 length, no discriminated
+// account type may ever have that length or it could be passed as a v1 escrow
+const _: () = assert!(
+    EscrowState::LEN != EscrowState::V1_LEN
+        && Config::LEN != EscrowState::V1_LEN
+        && DepositState::LEN != EscrowState::V1_LEN
+);
+
 pub struct EscrowAccount {
     pub key: Pubkey,
     pub info: EscrowState,
+    pub version: u8,
     pub program_id: Pubkey,
     pub is_writable: bool,
 }
 impl EscrowAccount {
     pub fn unpack(info: &AccountInfo) -> Result<EscrowAccount, EscrowError> {
+        let data = info
+            .try_borrow_data()
+            .map_err(|_| EscrowError::AccountBorrowFailed)?;
+        // only the legacy v1 layout predates discriminators, it is recognised by its exact length
+        if data.len() != EscrowState::V1_LEN {
+            check_discriminator::<EscrowState>(&data)?;
+        }
+        let (state, version) = match EscrowState::unpack_versioned(&data) {
+            Ok(s) => s,
+            Err(_) => return Err(EscrowError::DeserializeEscrowAccountError),
+        };
         Ok(EscrowAccount {
             key: *info.key,
-            info: match EscrowState::unpack(&info.try_borrow_data().unwrap()) {
+            info: state,
+            version,
+            program_id: *info.owner,
+            is_writable: info.is_writable,
+        })
+    }
+}
+pub struct ConfigAccount {
+    pub key: Pubkey,
+    pub info: Config,
+    pub program_id: Pubkey,
+    pub is_writable: bool,
+}
+impl ConfigAccount {
+    pub fn unpack(info: &AccountInfo) -> Result<ConfigAccount, EscrowError> {
+        let data = info
+            .try_borrow_data()
+            .map_err(|_| EscrowError::AccountBorrowFailed)?;
+        check_discriminator::<Config>(&data)?;
+        Ok(ConfigAccount {
+            key: *info.key,
+            info: match Config::unpack_from_slice(&data) {
+                Ok(s) => s,
+                Err(_) => return Err(EscrowError::InvalidConfigState),
+            },
+            program_id: *info.owner,
+            is_writable: info.is_writable,
+        })
+    }
+}
+pub struct DepositAccount {
+    pub key: Pubkey,
+    pub info: DepositState,
+    pub program_id: Pubkey,
+    pub is_writable: bool,
+}
+impl DepositAccount {
+    pub fn unpack(info: &AccountInfo) -> Result<DepositAccount, EscrowError> {
+        let data = info
+            .try_borrow_data()
+            .map_err(|_| EscrowError::AccountBorrowFailed)?;
+        check_discriminator::<DepositState>(&data)?;
+        Ok(DepositAccount {
+            key: *info.key,
+            info: match DepositState::unpack_from_slice(&data) {
                 Ok(s) => s,
-                Err(_) => return Err(EscrowError::DeserializeMintAccountError),
+                Err(_) => return Err(EscrowError::InvalidDepositState),
             },
             program_id: *info.owner,
             is_writable: info.is_writable,
@@ -124,19 +455,206 @@ impl EscrowAccount {
 pub struct MintAccount {
     pub key: Pubkey,
     pub info: Mint,
+    pub transfer_fee_config: Option<TransferFeeConfig>,
     pub program_id: Pubkey,
     pub is_writable: bool,
 }
 impl MintAccount {
     pub fn unpack(info: &AccountInfo) -> Result<MintAccount, EscrowError> {
+        let data = info
+            .try_borrow_data()
+            .map_err(|_| EscrowError::AccountBorrowFailed)?;
+        let mint = match StateWithExtensions::<Mint>::unpack(&data) {
+            Ok(s) => s,
+            Err(_) => return Err(EscrowError::DeserializeMintAccountError),
+        };
         Ok(MintAccount {
             key: *info.key,
-            info: match Mint::unpack(&info.try_borrow_data().unwrap()) {
-                Ok(s) => s,
-                Err(_) => return Err(EscrowError::DeserializeMintAccountError),
-            },
+            info: mint.base,
+            transfer_fee_config: mint.get_extension::<TransferFeeConfig>().ok().copied(),
             program_id: *info.owner,
             is_writable: info.is_writable,
         })
     }
+    /// Native SOL, moved as lamports by the system program
+    pub fn native() -> MintAccount {
+        MintAccount {
+            key: native_mint::ID,
+            info: Mint {
+                decimals: native_mint::DECIMALS,
+                is_initialized: true,
+                ..Mint::default()
+            },
+            transfer_fee_config: None,
+            program_id: system_program::ID,
+            is_writable: false,
+        }
+    }
+    pub fn is_native(&self) -> bool {
+        self.program_id.eq(&system_program::ID)
+    }
+    /// Returns the fee withheld when transferring `amount` during `epoch`
+    pub fn transfer_fee(&self, epoch: u64, amount: u64) -> Option<u64> {
+        match &self.transfer_fee_config {
+            Some(config) => config.calculate_epoch_fee(epoch, amount),
+            None => Some(0),
+        }
+    }
+    /// Returns the amount to transfer during `epoch` so that the receiver gets `net_amount`
+    pub fn pre_fee_amount(&self, epoch: u64, net_amount: u64) -> Option<u64> {
+        match &self.transfer_fee_config {
+            Some(config) => config
+                .get_epoch_fee(epoch)
+                .calculate_pre_fee_amount(net_amount),
+            None => Some(net_amount),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn config(fee_bps: u16) -> Config {
+        Config {
+            fee_bps,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn fee_rounds_down() {
+        // 0.3% of 999 is 2.997
+        assert_eq!(config(30).fee_for(999), Some(2));
+        // 0.01% of anything below 10_000 is less than one unit
+        assert_eq!(config(1).fee_for(9_999), Some(0));
+        assert_eq!(config(1).fee_for(10_000), Some(1));
+    }
+
+    #[test]
+    fn fee_bounds() {
+        assert_eq!(config(0).fee_for(u64::MAX), Some(0));
+        assert_eq!(config(MAX_FEE_BPS).fee_for(u64::MAX), Some(u64::MAX / 10));
+        assert_eq!(config(5_000).fee_for(u64::MAX), Some(u64::MAX / 2));
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let config = Config {
+            admin: Pubkey::new_unique(),
+            fee_recipient: Pubkey::new_unique(),
+            fee_bps: 25,
+            fee_side: FeeSide::A,
+            bump: 254,
+            paused: true,
+            pending_admin: Some(Pubkey::new_unique()),
+        };
+        let mut data = [0; Config::LEN];
+        Config::pack(config, &mut data).unwrap();
+        assert_eq!(&data[..8], &Config::DISCRIMINATOR);
+        assert_eq!(Config::unpack_from_slice(&data).unwrap(), config);
+    }
+
+    fn escrow(deposit_amount: u64, expected_amount: u64) -> EscrowState {
+        EscrowState {
+            expected_amount,
+            deposit_amount,
+            remaining_amount: deposit_amount,
+            ..EscrowState::default()
+        }
+    }
+
+    /// Fills the escrow in turn, returning the price of each fill
+    fn fill(state: &mut EscrowState, fills: &[u64]) -> Vec<u64> {
+        fills
+            .iter()
+            .map(|fill_amount| {
+                let price = state.price_for_fill(*fill_amount).unwrap();
+                state.remaining_amount -= fill_amount;
+                price
+            })
+            .collect()
+    }
+
+    #[test]
+    fn price_rounds_down_until_the_final_fill() {
+        // 2/3 of a unit of token B per unit of token A
+        assert_eq!(fill(&mut escrow(3, 2), &[1, 1, 1]), vec![0, 1, 1]);
+        assert_eq!(fill(&mut escrow(3, 2), &[2, 1]), vec![1, 1]);
+        // dust fills are free until the final one, which pays for the whole trade
+        assert_eq!(fill(&mut escrow(1_000, 1), &[1, 998, 1]), vec![0, 0, 1]);
+        assert_eq!(fill(&mut escrow(1_000, 1), &[1_000]), vec![1]);
+        // a price of more than one unit per unit of token A is never rounded
+        assert_eq!(fill(&mut escrow(4, 10), &[1, 3]), vec![2, 8]);
+    }
+
+    #[test]
+    fn only_the_v1_length_is_read_without_discriminator() {
+        let mut data = vec![0; EscrowState::LEN];
+        data[0] = 1;
+        assert_eq!(
+            EscrowState::unpack_versioned(&data[..EscrowState::V1_LEN])
+                .unwrap()
+                .1,
+            1
+        );
+        assert!(EscrowState::unpack_versioned(&data[..EscrowState::V1_LEN + 1]).is_err());
+        assert!(EscrowState::unpack_versioned(&data).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn escrow_unpack_never_panics(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+            let _ = EscrowState::unpack_from_slice(&data);
+            let _ = EscrowState::unpack_versioned(&data);
+        }
+
+        #[test]
+        fn escrow_unpack_never_panics_behind_valid_header(
+            body in proptest::collection::vec(any::<u8>(), EscrowState::LEN - 9),
+        ) {
+            let mut data = EscrowState::DISCRIMINATOR.to_vec();
+            data.push(ESCROW_STATE_VERSION);
+            data.extend_from_slice(&body);
+            let _ = EscrowState::unpack_from_slice(&data);
+            let _ = EscrowState::unpack_versioned(&data);
+        }
+
+        #[test]
+        fn escrow_unpack_v1_never_panics(
+            data in proptest::collection::vec(any::<u8>(), EscrowState::V1_LEN),
+        ) {
+            let _ = EscrowState::unpack_versioned(&data);
+        }
+
+        #[test]
+        fn fills_pay_exactly_the_expected_amount(
+            deposit_amount in 1..=u64::MAX,
+            expected_amount in 1..=u64::MAX,
+            splits in proptest::collection::vec(any::<u64>(), 0..8),
+        ) {
+            let mut state = escrow(deposit_amount, expected_amount);
+            let mut fills: Vec<u64> = splits
+                .iter()
+                .scan(deposit_amount, |remaining, split| {
+                    let fill_amount = split % *remaining;
+                    *remaining -= fill_amount;
+                    Some(fill_amount)
+                })
+                .collect();
+            fills.push(deposit_amount - fills.iter().sum::<u64>());
+            let prices = fill(&mut state, &fills);
+            prop_assert!(*prices.last().unwrap() > 0);
+            prop_assert_eq!(
+                prices.iter().map(|price| *price as u128).sum::<u128>(),
+                expected_amount as u128
+            );
+        }
+
+        #[test]
+        fn config_unpack_never_panics(data in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let _ = Config::unpack_from_slice(&data);
+        }
+    }
 }
@@ -0,0 +1,214 @@
+use solana_program::{
+    account_info::AccountInfo,
+    bpf_loader_upgradeable::{self, UpgradeableLoaderState},
+    program_utils::limited_deserialize,
+    pubkey::Pubkey,
+    system_program,
+};
+
+use crate::{
+    error::EscrowError,
+    id,
+    pda::{
+        create_deposit_address, create_escrow_address, find_config_address,
+        find_program_data_address,
+    },
+    state::{
+        ConfigAccount, DepositAccount, EscrowAccount, MintAccount, TokenAccount,
+        ESCROW_STATE_VERSION,
+    },
+};
+
+/// Checks that the account signed the transaction
+pub fn check_signer(info: &AccountInfo) -> Result<(), EscrowError> {
+    if !info.is_signer {
+        return Err(EscrowError::InvalidSigner);
+    }
+    Ok(())
+}
+
+/// Checks that the account was passed as writable
+pub fn check_writable(is_writable: bool) -> Result<(), EscrowError> {
+    if !is_writable {
+        return Err(EscrowError::AccountNotWritable);
+    }
+    Ok(())
+}
+
+/// Checks that a mint charging a transfer fee was passed as writable, closing a vault harvests
+/// the fees it withheld into the mint, any other mint may stay read-only
+pub fn check_harvest_mint(mint: &MintAccount) -> Result<(), EscrowError> {
+    if mint.transfer_fee_config.is_some() {
+        check_writable(mint.is_writable)?;
+    }
+    Ok(())
+}
+
+/// Checks that a program account is the expected program, returning `error` otherwise
+pub fn check_program_id(
+    info: &AccountInfo,
+    program_id: &Pubkey,
+    error: EscrowError,
+) -> Result<(), EscrowError> {
+    if !info.key.eq(program_id) {
+        return Err(error);
+    }
+    Ok(())
+}
+
+/// Checks the associated token program and the system program
+pub fn check_programs(
+    ata_program: &AccountInfo,
+    system_program: &AccountInfo,
+) -> Result<(), EscrowError> {
+    check_program_id(
+        ata_program,
+        &spl_associated_token_account::ID,
+        EscrowError::InvalidAssociatedTokenProgram,
+    )?;
+    check_program_id(
+        system_program,
+        &system_program::ID,
+        EscrowError::InvalidSystemProgram,
+    )
+}
+
+/// Returns true for the token program and the token-2022 program
+pub fn is_token_program(program_id: &Pubkey) -> bool {
+    program_id.eq(&spl_token::ID) || program_id.eq(&spl_token_2022::ID)
+}
+
+/// Checks that the token program account is the program owning the mint
+pub fn check_token_program(
+    token_program: &AccountInfo,
+    mint: &MintAccount,
+) -> Result<(), EscrowError> {
+    check_program_id(
+        token_program,
+        &mint.program_id,
+        EscrowError::InvalidTokenProgram,
+    )
+}
+
+/// Checks that the token account is owned by a token program
+pub fn check_token_account(token_account: &TokenAccount) -> Result<(), EscrowError> {
+    if !is_token_program(&token_account.program_id) {
+        return Err(EscrowError::InvalidTokenAccountOwner);
+    }
+    Ok(())
+}
+
+/// Checks that the token account belongs to `owner` and holds `mint`,
+/// returning `owner_error` or `mint_error` otherwise
+pub fn check_token_account_owner(
+    token_account: &TokenAccount,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    owner_error: EscrowError,
+    mint_error: EscrowError,
+) -> Result<(), EscrowError> {
+    if !token_account.info.owner.eq(owner) {
+        return Err(owner_error);
+    }
+    if !token_account.info.mint.eq(mint) {
+        return Err(mint_error);
+    }
+    Ok(())
+}
+
+/// Checks that the mint is owned by a token program
+pub fn check_mint(mint: &MintAccount) -> Result<(), EscrowError> {
+    if !is_token_program(&mint.program_id) {
+        return Err(EscrowError::InvalidMintOwner);
+    }
+    Ok(())
+}
+
+/// Checks that the escrow is owned by this program, initialized, lives at its PDA and uses the current layout
+pub fn check_escrow(escrow: &EscrowAccount) -> Result<(), EscrowError> {
+    check_escrow_any_version(escrow)?;
+    if escrow.version != ESCROW_STATE_VERSION {
+        return Err(EscrowError::EscrowNeedsMigration);
+    }
+    Ok(())
+}
+
+/// Checks the owner, the initialization and the address of an escrow stored in any layout version
+pub fn check_escrow_any_version(escrow: &EscrowAccount) -> Result<(), EscrowError> {
+    if !escrow.program_id.eq(&id()) {
+        return Err(EscrowError::InvalidEscrowOwner);
+    }
+    if !escrow.info.is_initialized {
+        return Err(EscrowError::EscrowNotInitialized);
+    }
+    match create_escrow_address(
+        &escrow.info.initializer_pubkey,
+        escrow.info.seed,
+        escrow.info.bump,
+    ) {
+        Ok(key) if key.eq(&escrow.key) => Ok(()),
+        _ => Err(EscrowError::EscrowKeyMismatch),
+    }
+}
+
+/// Checks that the deposit is owned by this program, initialized and lives at the deposit PDA of its escrow
+pub fn check_deposit(deposit: &DepositAccount) -> Result<(), EscrowError> {
+    if !deposit.program_id.eq(&id()) {
+        return Err(EscrowError::InvalidDepositOwner);
+    }
+    if !deposit.info.is_initialized {
+        return Err(EscrowError::DepositNotInitialized);
+    }
+    match create_deposit_address(&deposit.info.escrow, deposit.info.bump) {
+        Ok(key) if key.eq(&deposit.key) => Ok(()),
+        _ => Err(EscrowError::DepositKeyMismatch),
+    }
+}
+
+/// Checks that the config is owned by this program and lives at the config PDA
+pub fn check_config(config: &ConfigAccount) -> Result<(), EscrowError> {
+    if !config.program_id.eq(&id()) {
+        return Err(EscrowError::InvalidConfigOwner);
+    }
+    if !find_config_address().0.eq(&config.key) {
+        return Err(EscrowError::ConfigKeyMismatch);
+    }
+    Ok(())
+}
+
+/// Checks that `authority` is the upgrade authority recorded in the program data account of this program
+pub fn check_upgrade_authority(
+    program_data: &AccountInfo,
+    authority: &Pubkey,
+) -> Result<(), EscrowError> {
+    if !program_data.owner.eq(&bpf_loader_upgradeable::ID)
+        || !find_program_data_address().0.eq(program_data.key)
+    {
+        return Err(EscrowError::InvalidProgramData);
+    }
+    let data = program_data
+        .try_borrow_data()
+        .map_err(|_| EscrowError::AccountBorrowFailed)?;
+    match limited_deserialize(
+        &data,
+        UpgradeableLoaderState::size_of_programdata_metadata() as u64,
+    ) {
+        Ok(UpgradeableLoaderState::ProgramData {
+            upgrade_authority_address: Some(upgrade_authority),
+            ..
+        }) if upgrade_authority.eq(authority) => Ok(()),
+        // an immutable program has no upgrade authority
+        Ok(UpgradeableLoaderState::ProgramData { .. }) => {
+            Err(EscrowError::UpgradeAuthorityMismatch)
+        }
+        _ => Err(EscrowError::InvalidProgramData),
+    }
+}
+
+/// Checks that the escrow account has not been created yet
+pub fn check_uninitialized(info: &AccountInfo) -> Result<(), EscrowError> {
+    if !info.data_is_empty() || !info.owner.eq(&system_program::ID) {
+        return Err(EscrowError::EscrowAlreadyInitialized);
+    }
+    Ok(())
+}
@@ -0,0 +1,2362 @@
+//! Integration tests running the program on `solana-program-test`, without a validator.
+//!
+//! Every reachable [EscrowError] variant is exercised. `InvalidEscrowVault` is never returned,
+//! `InvalidEscrowState` only guards packing a state into an account of the wrong size,
+//! `AccountBorrowFailed`, `SysvarUnavailable` and `ReallocFailed` guard runtime failures,
+//! `ArithmeticOverflow` and `TransferFeeTooHigh` guard calculations that cannot fail with amounts
+//! bounded by the token supply and the rounding of token-2022 transfer fees, `TransferFeeTooHigh`
+//! also guarding a settlement after a transfer fee raised since the deposit, which takes epochs.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use borsh::BorshDeserialize;
+use solana_escrow_plus::{
+    check_program_account,
+    error::EscrowError,
+    events::{EscrowCancelled, EscrowCreated, EscrowEvent, EscrowExchanged},
+    id,
+    instruction::{self, EscrowInstruction},
+    merkle::{hash_pair, taker_leaf},
+    pda::{
+        find_config_address, find_deposit_address, find_escrow_address, find_program_data_address,
+        token_account_address,
+    },
+    processor::Processor,
+    state::{
+        Config, DepositState, Discriminator, EscrowState, FeeSide, NativeSide, ESCROW_STATE_VERSION,
+    },
+};
+use solana_program::{
+    account_info::AccountInfo,
+    bpf_loader_upgradeable,
+    entrypoint::ProgramResult,
+    instruction::Instruction,
+    program_pack::Pack,
+    program_stubs::{self, SyscallStubs},
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction, system_program,
+};
+use solana_program_test::{
+    processor, BanksClientError, ProgramTest, ProgramTestBanksClientExt, ProgramTestContext,
+};
+use solana_sdk::{
+    account::{Account, AccountSharedData},
+    clock::Clock,
+    instruction::InstructionError,
+    signature::{Keypair, Signature, Signer},
+    transaction::{Transaction, TransactionError},
+};
+use spl_token::native_mint;
+use spl_token_2022::{
+    extension::{
+        transfer_fee::{instruction::initialize_transfer_fee_config, TransferFeeConfig},
+        BaseStateWithExtensions, ExtensionType, StateWithExtensions,
+    },
+    state::{Account as TokenAccountState, Mint},
+};
+use std::{collections::HashSet, sync::Once};
+
+const DEPOSIT: u64 = 1_000;
+const EXPECTED: u64 = 5_000;
+const TAKER_B: u64 = 100_000;
+
+fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    check_program_account(program_id)?;
+    // the processor ties the account slice to the lifetime of the accounts, as the entrypoint does
+    let accounts = Box::leak(accounts.to_vec().into_boxed_slice());
+    Processor::process(accounts, instruction_data).map_err(|e| e.print_into())
+}
+
+async fn start() -> ProgramTestContext {
+    let ctx = ProgramTest::new("solana_escrow_plus", id(), processor!(process_instruction))
+        .start_with_context()
+        .await;
+    // the harness installs its stubs when the first test starts
+    static LOG_DATA_STUBS: Once = Once::new();
+    LOG_DATA_STUBS.call_once(|| {
+        let stubs = program_stubs::set_syscall_stubs(Box::new(DefaultSyscallStubs));
+        program_stubs::set_syscall_stubs(Box::new(LogDataStubs(stubs)));
+    });
+    ctx
+}
+
+struct DefaultSyscallStubs;
+impl SyscallStubs for DefaultSyscallStubs {}
+
+/// The syscall stubs of the harness, except that `sol_log_data` reaches the transaction logs
+/// instead of stdout, the harness having no way to log it as the runtime does
+struct LogDataStubs(Box<dyn SyscallStubs>);
+impl SyscallStubs for LogDataStubs {
+    fn sol_log(&self, message: &str) {
+        self.0.sol_log(message)
+    }
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        self.0
+            .sol_invoke_signed(instruction, account_infos, signers_seeds)
+    }
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_clock_sysvar(var_addr)
+    }
+    fn sol_get_epoch_schedule_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_epoch_schedule_sysvar(var_addr)
+    }
+    fn sol_get_epoch_rewards_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_epoch_rewards_sysvar(var_addr)
+    }
+    fn sol_get_fees_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_fees_sysvar(var_addr)
+    }
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_rent_sysvar(var_addr)
+    }
+    fn sol_get_last_restart_slot(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_last_restart_slot(var_addr)
+    }
+    fn sol_get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+        self.0.sol_get_return_data()
+    }
+    fn sol_set_return_data(&self, data: &[u8]) {
+        self.0.sol_set_return_data(data)
+    }
+    fn sol_get_stack_height(&self) -> u64 {
+        self.0.sol_get_stack_height()
+    }
+    fn sol_log_data(&self, fields: &[&[u8]]) {
+        let fields: Vec<String> = fields.iter().map(|field| BASE64.encode(field)).collect();
+        self.0
+            .sol_log(&format!("Program data: {}", fields.join(" ")))
+    }
+}
+
+/// Decodes the events a transaction logged, as an indexer reads them from `Program data: <base64>`.
+/// The stubs of the harness log through `sol_log`, which adds its own `Program log: ` prefix.
+fn decode_events(logs: &[String]) -> Vec<EscrowEvent> {
+    logs.iter()
+        .filter_map(|log| {
+            log.trim_start_matches("Program log: ")
+                .strip_prefix("Program data: ")
+        })
+        .map(|data| EscrowEvent::try_from_slice(&BASE64.decode(data).unwrap()).unwrap())
+        .collect()
+}
+
+/// Two traders holding token A and token B, the initializer having deposited nothing yet
+struct Env {
+    ctx: ProgramTestContext,
+    initializer: Keypair,
+    taker: Keypair,
+    mint_a: Pubkey,
+    mint_b: Pubkey,
+    token_program: Pubkey,
+    /// fee recipient and fee side of the config, the builders pointing the fee receiver at them
+    fee_recipient: Pubkey,
+    fee_side: FeeSide,
+    /// signatures already sent, the bank drops a transaction sent again under the same blockhash
+    sent: HashSet<Signature>,
+}
+
+impl Env {
+    async fn new() -> Env {
+        Env::with_token_program(spl_token::ID).await
+    }
+
+    async fn with_token_program(token_program: Pubkey) -> Env {
+        Env::with_mints(token_program, None).await
+    }
+
+    /// Traders of two token-2022 mints charging a transfer fee of `basis_points`
+    async fn with_transfer_fee(basis_points: u16) -> Env {
+        Env::with_mints(spl_token_2022::ID, Some(basis_points)).await
+    }
+
+    async fn with_mints(token_program: Pubkey, transfer_fee_bps: Option<u16>) -> Env {
+        let ctx = start().await;
+        let mut env = Env {
+            ctx,
+            initializer: Keypair::new(),
+            taker: Keypair::new(),
+            mint_a: Pubkey::default(),
+            mint_b: Pubkey::default(),
+            token_program,
+            fee_recipient: Pubkey::default(),
+            fee_side: FeeSide::B,
+            sent: HashSet::new(),
+        };
+        env.airdrop(&env.initializer.pubkey(), 10_000_000_000).await;
+        env.airdrop(&env.taker.pubkey(), 10_000_000_000).await;
+        env.mint_a = env
+            .create_mint_with_transfer_fee(&token_program, transfer_fee_bps)
+            .await;
+        env.mint_b = env
+            .create_mint_with_transfer_fee(&token_program, transfer_fee_bps)
+            .await;
+        let (initializer, taker) = (env.initializer.pubkey(), env.taker.pubkey());
+        let (mint_a, mint_b) = (env.mint_a, env.mint_b);
+        env.mint_to(&mint_a, &initializer, DEPOSIT).await;
+        env.mint_to(&mint_b, &taker, TAKER_B).await;
+        env
+    }
+
+    async fn process(
+        &mut self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+    ) -> Result<(), BanksClientError> {
+        let tx = self.transaction(instructions, signers).await;
+        self.ctx.banks_client.process_transaction(tx).await
+    }
+
+    /// Processes a transaction that must succeed, returning the events it logged
+    async fn process_events(
+        &mut self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+    ) -> Vec<EscrowEvent> {
+        let tx = self.transaction(instructions, signers).await;
+        let result = self
+            .ctx
+            .banks_client
+            .process_transaction_with_metadata(tx)
+            .await
+            .unwrap();
+        result.result.unwrap();
+        decode_events(&result.metadata.unwrap().log_messages)
+    }
+
+    async fn transaction(
+        &mut self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+    ) -> Transaction {
+        let payer = self.ctx.payer.insecure_clone();
+        let mut all_signers = vec![&payer];
+        all_signers.extend_from_slice(signers);
+        let blockhash = self.ctx.banks_client.get_latest_blockhash().await.unwrap();
+        let mut tx = Transaction::new_signed_with_payer(
+            instructions,
+            Some(&payer.pubkey()),
+            &all_signers,
+            blockhash,
+        );
+        if !self.sent.insert(tx.signatures[0]) {
+            let blockhash = self
+                .ctx
+                .banks_client
+                .get_new_latest_blockhash(&blockhash)
+                .await
+                .unwrap();
+            tx.sign(&all_signers, blockhash);
+            self.sent.insert(tx.signatures[0]);
+        }
+        tx
+    }
+
+    async fn airdrop(&mut self, to: &Pubkey, lamports: u64) {
+        let payer = self.ctx.payer.pubkey();
+        self.process(&[system_instruction::transfer(&payer, to, lamports)], &[])
+            .await
+            .unwrap();
+    }
+
+    async fn create_mint(&mut self, token_program: &Pubkey) -> Pubkey {
+        self.create_mint_with_transfer_fee(token_program, None)
+            .await
+    }
+
+    /// Creates a mint, charging a transfer fee of `transfer_fee_bps` without maximum if given
+    async fn create_mint_with_transfer_fee(
+        &mut self,
+        token_program: &Pubkey,
+        transfer_fee_bps: Option<u16>,
+    ) -> Pubkey {
+        let mint = Keypair::new();
+        let payer = self.ctx.payer.pubkey();
+        let space = match transfer_fee_bps {
+            Some(_) => ExtensionType::get_account_len::<Mint>(&[ExtensionType::TransferFeeConfig]),
+            None => Mint::LEN,
+        };
+        let rent = Rent::default().minimum_balance(space);
+        let mut instructions = vec![system_instruction::create_account(
+            &payer,
+            &mint.pubkey(),
+            rent,
+            space as u64,
+            token_program,
+        )];
+        if let Some(basis_points) = transfer_fee_bps {
+            instructions.push(
+                initialize_transfer_fee_config(
+                    token_program,
+                    &mint.pubkey(),
+                    Some(&payer),
+                    Some(&payer),
+                    basis_points,
+                    u64::MAX,
+                )
+                .unwrap(),
+            );
+        }
+        instructions.push(
+            spl_token_2022::instruction::initialize_mint2(
+                token_program,
+                &mint.pubkey(),
+                &payer,
+                None,
+                6,
+            )
+            .unwrap(),
+        );
+        self.process(&instructions, &[&mint]).await.unwrap();
+        mint.pubkey()
+    }
+
+    /// Creates the ATA of `owner` if needed and mints `amount` to it
+    async fn mint_to(&mut self, mint: &Pubkey, owner: &Pubkey, amount: u64) -> Pubkey {
+        let token_program = self.account(mint).await.unwrap().owner;
+        let payer = self.ctx.payer.pubkey();
+        let ata = token_account_address(owner, mint, &token_program);
+        let mut instructions = vec![
+            spl_associated_token_account::instruction::create_associated_token_account_idempotent(
+                &payer,
+                owner,
+                mint,
+                &token_program,
+            ),
+        ];
+        if amount > 0 {
+            instructions.push(
+                spl_token_2022::instruction::mint_to(
+                    &token_program,
+                    mint,
+                    &ata,
+                    &payer,
+                    &[],
+                    amount,
+                )
+                .unwrap(),
+            );
+        }
+        self.process(&instructions, &[]).await.unwrap();
+        ata
+    }
+
+    /// Creates a token account of `owner` that is not its ATA
+    async fn create_token_account(&mut self, mint: &Pubkey, owner: &Pubkey) -> Pubkey {
+        let token_program = self.account(mint).await.unwrap().owner;
+        let account = Keypair::new();
+        let payer = self.ctx.payer.pubkey();
+        let rent = Rent::default().minimum_balance(spl_token::state::Account::LEN);
+        self.process(
+            &[
+                system_instruction::create_account(
+                    &payer,
+                    &account.pubkey(),
+                    rent,
+                    spl_token::state::Account::LEN as u64,
+                    &token_program,
+                ),
+                spl_token_2022::instruction::initialize_account3(
+                    &token_program,
+                    &account.pubkey(),
+                    mint,
+                    owner,
+                )
+                .unwrap(),
+            ],
+            &[&account],
+        )
+        .await
+        .unwrap();
+        account.pubkey()
+    }
+
+    async fn account(&mut self, key: &Pubkey) -> Option<Account> {
+        self.ctx.banks_client.get_account(*key).await.unwrap()
+    }
+
+    async fn lamports(&mut self, key: &Pubkey) -> u64 {
+        self.account(key)
+            .await
+            .map_or(0, |account| account.lamports)
+    }
+
+    async fn token_balance(&mut self, key: &Pubkey) -> u64 {
+        let account = self.account(key).await.expect("token account exists");
+        StateWithExtensions::<TokenAccountState>::unpack(&account.data)
+            .unwrap()
+            .base
+            .amount
+    }
+
+    async fn escrow_state(&mut self, seed: u64) -> EscrowState {
+        let escrow = self.escrow(seed);
+        let account = self.account(&escrow).await.expect("escrow exists");
+        EscrowState::unpack_from_slice(&account.data).unwrap()
+    }
+
+    async fn deposit_state(&mut self, seed: u64) -> DepositState {
+        let deposit = self.deposit(seed);
+        let account = self.account(&deposit).await.expect("deposit exists");
+        DepositState::unpack_from_slice(&account.data).unwrap()
+    }
+
+    async fn set_unix_timestamp(&mut self, unix_timestamp: i64) {
+        let clock = self.ctx.banks_client.get_sysvar::<Clock>().await.unwrap();
+        self.ctx.set_sysvar(&Clock {
+            unix_timestamp,
+            ..clock
+        });
+    }
+
+    async fn now(&mut self) -> i64 {
+        self.ctx
+            .banks_client
+            .get_sysvar::<Clock>()
+            .await
+            .unwrap()
+            .unix_timestamp
+    }
+
+    fn escrow(&self, seed: u64) -> Pubkey {
+        find_escrow_address(&self.initializer.pubkey(), seed).0
+    }
+
+    fn vault(&self, seed: u64) -> Pubkey {
+        token_account_address(&self.escrow(seed), &self.mint_a, &self.token_program)
+    }
+
+    fn deposit(&self, seed: u64) -> Pubkey {
+        find_deposit_address(&self.escrow(seed)).0
+    }
+
+    fn deposit_vault(&self, seed: u64) -> Pubkey {
+        token_account_address(&self.deposit(seed), &self.mint_b, &self.token_program)
+    }
+
+    fn initializer_ata(&self, mint: &Pubkey) -> Pubkey {
+        token_account_address(&self.initializer.pubkey(), mint, &self.token_program)
+    }
+
+    fn taker_ata(&self, mint: &Pubkey) -> Pubkey {
+        token_account_address(&self.taker.pubkey(), mint, &self.token_program)
+    }
+
+    fn init_ix(&self, seed: u64, expiry: Option<i64>) -> Instruction {
+        instruction::init_escrow(
+            &id(),
+            &self.initializer.pubkey(),
+            &self.mint_a,
+            &self.mint_b,
+            &self.initializer_ata(&self.mint_a),
+            &self.token_program,
+            DEPOSIT,
+            EXPECTED,
+            seed,
+            expiry,
+            NativeSide::None,
+            None,
+            None,
+        )
+    }
+
+    fn exchange_ix(&self, seed: u64, fill_amount: u64, proof: Vec<[u8; 32]>) -> Instruction {
+        instruction::exchange(
+            &id(),
+            &self.taker.pubkey(),
+            &self.initializer.pubkey(),
+            &self.mint_a,
+            &self.mint_b,
+            &self.taker_ata(&self.mint_b),
+            &self.token_program,
+            &self.token_program,
+            seed,
+            EXPECTED,
+            fill_amount,
+            0,
+            proof,
+            &self.fee_recipient,
+            self.fee_side,
+        )
+    }
+
+    fn cancel_ix(&self, seed: u64) -> Instruction {
+        instruction::cancel(
+            &id(),
+            &self.initializer.pubkey(),
+            &self.mint_a,
+            &self.initializer_ata(&self.mint_a),
+            &self.token_program,
+            seed,
+        )
+    }
+
+    fn expire_ix(&self, seed: u64) -> Instruction {
+        instruction::expire(
+            &id(),
+            &self.taker.pubkey(),
+            &self.initializer.pubkey(),
+            &self.mint_a,
+            &self.token_program,
+            seed,
+        )
+    }
+
+    fn deposit_ix(&self, seed: u64, proof: Vec<[u8; 32]>) -> Instruction {
+        instruction::deposit(
+            &id(),
+            &self.taker.pubkey(),
+            &self.initializer.pubkey(),
+            &self.mint_b,
+            &self.taker_ata(&self.mint_b),
+            &self.token_program,
+            seed,
+            EXPECTED,
+            proof,
+        )
+    }
+
+    fn settle_ix(&self, seed: u64, settler: &Pubkey) -> Instruction {
+        instruction::settle(
+            &id(),
+            settler,
+            &self.initializer.pubkey(),
+            &self.taker.pubkey(),
+            &self.mint_a,
+            &self.mint_b,
+            &self.token_program,
+            &self.token_program,
+            seed,
+            &self.fee_recipient,
+            self.fee_side,
+        )
+    }
+
+    fn withdraw_deposit_ix(&self, seed: u64) -> Instruction {
+        instruction::withdraw_deposit(
+            &id(),
+            &self.taker.pubkey(),
+            &self.initializer.pubkey(),
+            &self.mint_b,
+            &self.taker_ata(&self.mint_b),
+            &self.token_program,
+            seed,
+        )
+    }
+
+    async fn init(&mut self, seed: u64, expiry: Option<i64>) {
+        let ix = self.init_ix(seed, expiry);
+        let initializer = self.initializer.insecure_clone();
+        self.process(&[ix], &[&initializer]).await.unwrap();
+    }
+
+    async fn init_ix_result(&mut self, ix: Instruction) -> Result<(), BanksClientError> {
+        let initializer = self.initializer.insecure_clone();
+        self.process(&[ix], &[&initializer]).await
+    }
+
+    async fn taker_ix_result(&mut self, ix: Instruction) -> Result<(), BanksClientError> {
+        let taker = self.taker.insecure_clone();
+        self.process(&[ix], &[&taker]).await
+    }
+
+    async fn init_config(
+        &mut self,
+        admin: &Keypair,
+        fee_recipient: &Pubkey,
+        fee_bps: u16,
+        fee_side: FeeSide,
+    ) {
+        self.airdrop(&admin.pubkey(), 1_000_000_000).await;
+        self.set_upgrade_authority(Some(&admin.pubkey()));
+        let ix = instruction::init_config(&id(), &admin.pubkey(), fee_recipient, fee_bps, fee_side);
+        self.process(&[ix], &[admin]).await.unwrap();
+        self.fee_recipient = *fee_recipient;
+        self.fee_side = fee_side;
+    }
+
+    /// Writes the program data account the upgradeable loader keeps for the program
+    fn set_upgrade_authority(&mut self, authority: Option<&Pubkey>) {
+        let (program_data, _) = find_program_data_address();
+        self.set_account(
+            &program_data,
+            &bpf_loader_upgradeable::ID,
+            program_data_bytes(authority),
+        );
+    }
+
+    /// Overwrites an account, bypassing the runtime checks
+    fn set_account(&mut self, key: &Pubkey, owner: &Pubkey, data: Vec<u8>) {
+        let mut account = AccountSharedData::new(
+            Rent::default().minimum_balance(data.len()),
+            data.len(),
+            owner,
+        );
+        account.set_data_from_slice(&data);
+        self.ctx.set_account(key, &account);
+    }
+}
+
+fn assert_error(result: Result<(), BanksClientError>, error: EscrowError) {
+    assert_custom_error(result, error.code());
+}
+
+fn assert_custom_error(result: Result<(), BanksClientError>, code: u32) {
+    match result.expect_err("the transaction should fail").unwrap() {
+        TransactionError::InstructionError(_, InstructionError::Custom(actual)) => {
+            assert_eq!(actual, code, "unexpected custom error")
+        }
+        other => panic!("unexpected error {other:?}"),
+    }
+}
+
+/// The bincode encoding of `UpgradeableLoaderState::ProgramData`, without any program bytes
+fn program_data_bytes(upgrade_authority: Option<&Pubkey>) -> Vec<u8> {
+    let mut data = 3u32.to_le_bytes().to_vec();
+    data.extend_from_slice(&0u64.to_le_bytes());
+    match upgrade_authority {
+        Some(authority) => {
+            data.push(1);
+            data.extend_from_slice(authority.as_ref());
+        }
+        None => data.extend_from_slice(&[0; 33]),
+    }
+    data
+}
+
+/// Serializes an escrow state the way the headerless v1 layout stored it, up to the seed
+fn pack_v1(state: &EscrowState) -> Vec<u8> {
+    let mut data = vec![state.is_initialized as u8];
+    data.extend_from_slice(state.initializer_pubkey.as_ref());
+    data.extend_from_slice(state.mint_a.as_ref());
+    data.extend_from_slice(state.mint_b.as_ref());
+    data.extend_from_slice(&state.expected_amount.to_le_bytes());
+    data.push(state.bump);
+    data.extend_from_slice(&state.seed.to_le_bytes());
+    assert_eq!(data.len(), EscrowState::V1_LEN);
+    data
+}
+
+fn pack_escrow(state: EscrowState) -> Vec<u8> {
+    let mut data = vec![0; EscrowState::LEN];
+    EscrowState::pack(state, &mut data).unwrap();
+    data
+}
+
+// happy paths
+
+#[tokio::test]
+async fn init_escrow_funds_the_vault() {
+    let mut env = Env::new().await;
+    env.init(1, None).await;
+
+    let state = env.escrow_state(1).await;
+    assert!(state.is_initialized);
+    assert_eq!(state.initializer_pubkey, env.initializer.pubkey());
+    assert_eq!(state.mint_a, env.mint_a);
+    assert_eq!(state.mint_b, env.mint_b);
+    assert_eq!(state.expected_amount, EXPECTED);
+    assert_eq!(state.deposit_amount, DEPOSIT);
+    assert_eq!(state.remaining_amount, DEPOSIT);
+    let escrow = env.account(&env.escrow(1)).await.unwrap();
+    assert_eq!(escrow.owner, id());
+    assert_eq!(&escrow.data[..8], &EscrowState::DISCRIMINATOR);
+    assert_eq!(escrow.data[8], ESCROW_STATE_VERSION);
+
+    let (vault, initializer_a) = (env.vault(1), env.initializer_ata(&env.mint_a));
+    assert_eq!(env.token_balance(&vault).await, DEPOSIT);
+    assert_eq!(env.token_balance(&initializer_a).await, 0);
+}
+
+#[tokio::test]
+async fn exchange_swaps_and_closes_the_escrow() {
+    let mut env = Env::new().await;
+    env.init(1, None).await;
+    let initializer = env.initializer.pubkey();
+    let (escrow, vault) = (env.escrow(1), env.vault(1));
+    let escrow_rent = env.lamports(&escrow).await;
+    let vault_rent = env.lamports(&vault).await;
+    let initializer_lamports = env.lamports(&initializer).await;
+
+    let ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    let token_b_receiver = env.initializer_ata(&env.mint_b);
+    env.taker_ix_result(ix).await.unwrap();
+
+    let (taker_a, taker_b) = (env.taker_ata(&env.mint_a), env.taker_ata(&env.mint_b));
+    assert_eq!(env.token_balance(&taker_a).await, DEPOSIT);
+    assert_eq!(env.token_balance(&taker_b).await, TAKER_B - EXPECTED);
+    assert_eq!(env.token_balance(&token_b_receiver).await, EXPECTED);
+    assert!(env.account(&escrow).await.is_none());
+    assert!(env.account(&vault).await.is_none());
+    assert_eq!(
+        env.lamports(&initializer).await,
+        initializer_lamports + escrow_rent + vault_rent
+    );
+}
+
+#[tokio::test]
+async fn partial_fills_keep_the_escrow_open_until_filled() {
+    let mut env = Env::new().await;
+    env.init(1, None).await;
+    let (escrow, vault) = (env.escrow(1), env.vault(1));
+    let taker_a = env.taker_ata(&env.mint_a);
+
+    env.taker_ix_result(env.exchange_ix(1, 300, vec![]))
+        .await
+        .unwrap();
+    assert_eq!(env.escrow_state(1).await.remaining_amount, DEPOSIT - 300);
+    assert_eq!(env.token_balance(&vault).await, DEPOSIT - 300);
+    assert_eq!(env.token_balance(&taker_a).await, 300);
+
+    env.taker_ix_result(env.exchange_ix(1, DEPOSIT - 300, vec![]))
+        .await
+        .unwrap();
+    assert_eq!(env.token_balance(&taker_a).await, DEPOSIT);
+    let taker_b = env.taker_ata(&env.mint_b);
+    assert_eq!(env.token_balance(&taker_b).await, TAKER_B - EXPECTED);
+    assert!(env.account(&escrow).await.is_none());
+}
+
+#[tokio::test]
+async fn cancel_refunds_and_closes_the_escrow() {
+    let mut env = Env::new().await;
+    env.init(1, None).await;
+    let (escrow, vault) = (env.escrow(1), env.vault(1));
+
+    let ix = env.cancel_ix(1);
+    env.init_ix_result(ix).await.unwrap();
+
+    let initializer_a = env.initializer_ata(&env.mint_a);
+    assert_eq!(env.token_balance(&initializer_a).await, DEPOSIT);
+    assert!(env.account(&escrow).await.is_none());
+    assert!(env.account(&vault).await.is_none());
+}
+
+#[tokio::test]
+async fn events_decode_from_the_transaction_logs() {
+    let mut env = Env::new().await;
+    let (initializer, taker) = (env.initializer.insecure_clone(), env.taker.insecure_clone());
+    let (escrow, mint_a, mint_b) = (env.escrow(1), env.mint_a, env.mint_b);
+
+    let events = env
+        .process_events(&[env.init_ix(1, Some(i64::MAX))], &[&initializer])
+        .await;
+    assert_eq!(
+        events,
+        vec![EscrowEvent::Created(EscrowCreated {
+            escrow,
+            initializer: initializer.pubkey(),
+            mint_a,
+            mint_b,
+            deposit_amount: DEPOSIT,
+            expected_amount: EXPECTED,
+            seed: 1,
+            expiry: Some(i64::MAX),
+        })]
+    );
+
+    let events = env
+        .process_events(&[env.exchange_ix(1, 300, vec![])], &[&taker])
+        .await;
+    assert_eq!(
+        events,
+        vec![EscrowEvent::Exchanged(EscrowExchanged {
+            escrow,
+            initializer: initializer.pubkey(),
+            taker: taker.pubkey(),
+            mint_a,
+            mint_b,
+            amount_a: 300,
+            amount_b: EXPECTED * 300 / DEPOSIT,
+            fee_amount: 0,
+            remaining_amount: DEPOSIT - 300,
+            seed: 1,
+        })]
+    );
+
+    let events = env
+        .process_events(&[env.cancel_ix(1)], &[&initializer])
+        .await;
+    assert_eq!(
+        events,
+        vec![EscrowEvent::Cancelled(EscrowCancelled {
+            escrow,
+            initializer: initializer.pubkey(),
+            mint_a,
+            refund_amount: DEPOSIT - 300,
+            seed: 1,
+            expired: false,
+        })]
+    );
+}
+
+#[tokio::test]
+async fn expire_refunds_an_expired_escrow() {
+    let mut env = Env::new().await;
+    let now = env.now().await;
+    env.init(1, Some(now + 100)).await;
+
+    env.set_unix_timestamp(now + 100).await;
+    env.taker_ix_result(env.expire_ix(1)).await.unwrap();
+
+    let initializer_a = env.initializer_ata(&env.mint_a);
+    assert_eq!(env.token_balance(&initializer_a).await, DEPOSIT);
+    assert!(env.account(&env.escrow(1)).await.is_none());
+}
+
+#[tokio::test]
+async fn exchange_token_2022_tokens() {
+    let mut env = Env::with_token_program(spl_token_2022::ID).await;
+    env.init(1, None).await;
+    env.taker_ix_result(env.exchange_ix(1, DEPOSIT, vec![]))
+        .await
+        .unwrap();
+
+    let taker_a = env.taker_ata(&env.mint_a);
+    assert_eq!(env.token_balance(&taker_a).await, DEPOSIT);
+    assert!(env.account(&env.escrow(1)).await.is_none());
+}
+
+#[tokio::test]
+async fn exchange_pays_transfer_fees_on_top() {
+    // 1% on both mints
+    let mut env = Env::with_transfer_fee(100).await;
+    env.init(1, None).await;
+    // the vault only receives what is left after the fee of token A
+    let state = env.escrow_state(1).await;
+    assert_eq!(state.deposit_amount, DEPOSIT - 10);
+    let vault = env.vault(1);
+    assert_eq!(env.token_balance(&vault).await, DEPOSIT - 10);
+
+    // the minimum of the taker is net of the fee withheld when token A leaves the vault
+    let mut ix = env.exchange_ix(1, state.deposit_amount, vec![]);
+    ix.data = EscrowInstruction::Exchange {
+        amount: EXPECTED,
+        fill_amount: state.deposit_amount,
+        min_amount_a: state.deposit_amount,
+        proof: vec![],
+    }
+    .pack();
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::AmountABelowMinimum,
+    );
+
+    env.taker_ix_result(env.exchange_ix(1, state.deposit_amount, vec![]))
+        .await
+        .unwrap();
+    // the initializer gets exactly the expected amount, the taker paying the fee of token B on top
+    let initializer_b = env.initializer_ata(&env.mint_b);
+    assert_eq!(env.token_balance(&initializer_b).await, EXPECTED);
+    let taker_b = env.taker_ata(&env.mint_b);
+    assert_eq!(env.token_balance(&taker_b).await, TAKER_B - EXPECTED - 51);
+    let taker_a = env.taker_ata(&env.mint_a);
+    assert_eq!(env.token_balance(&taker_a).await, DEPOSIT - 10 - 10);
+    // the fees withheld by the vault are harvested into the mint so the vault can be closed
+    assert!(env.account(&vault).await.is_none());
+    assert!(env.account(&env.escrow(1)).await.is_none());
+    let mint_a = env.mint_a;
+    let mint_a = env.account(&mint_a).await.unwrap();
+    let mint_a = StateWithExtensions::<Mint>::unpack(&mint_a.data).unwrap();
+    let withheld: u64 = mint_a
+        .get_extension::<TransferFeeConfig>()
+        .unwrap()
+        .withheld_amount
+        .into();
+    assert_eq!(withheld, 10);
+}
+
+#[tokio::test]
+async fn exchange_native_sol_for_tokens() {
+    let mut env = Env::new().await;
+    let initializer = env.initializer.pubkey();
+    let taker = env.taker.pubkey();
+    let ix = instruction::init_escrow(
+        &id(),
+        &initializer,
+        &native_mint::ID,
+        &env.mint_b,
+        &initializer,
+        &system_program::ID,
+        DEPOSIT,
+        EXPECTED,
+        1,
+        None,
+        NativeSide::A,
+        None,
+        None,
+    );
+    env.init_ix_result(ix).await.unwrap();
+    let escrow = env.escrow(1);
+    let rent = Rent::default().minimum_balance(EscrowState::LEN);
+    assert_eq!(env.lamports(&escrow).await, rent + DEPOSIT);
+
+    let taker_lamports = env.lamports(&taker).await;
+    let ix = instruction::exchange(
+        &id(),
+        &taker,
+        &initializer,
+        &native_mint::ID,
+        &env.mint_b,
+        &env.taker_ata(&env.mint_b),
+        &system_program::ID,
+        &env.token_program,
+        1,
+        EXPECTED,
+        DEPOSIT,
+        0,
+        vec![],
+        &Pubkey::default(),
+        FeeSide::B,
+    );
+    let token_b_receiver = env.initializer_ata(&env.mint_b);
+    env.taker_ix_result(ix).await.unwrap();
+
+    assert_eq!(env.token_balance(&token_b_receiver).await, EXPECTED);
+    // the taker paid the rent of the token B receiver out of the SOL received
+    let ata_rent = Rent::default().minimum_balance(TokenAccountState::LEN);
+    assert_eq!(
+        env.lamports(&taker).await,
+        taker_lamports + DEPOSIT - ata_rent
+    );
+    assert!(env.account(&escrow).await.is_none());
+}
+
+#[tokio::test]
+async fn exchange_pays_the_protocol_fee() {
+    let mut env = Env::new().await;
+    let admin = Keypair::new();
+    let fee_recipient = Pubkey::new_unique();
+    env.init_config(&admin, &fee_recipient, 100, FeeSide::B)
+        .await;
+    env.init(1, None).await;
+
+    // the taker creates the fee receiver
+    let fee_receiver = token_account_address(&fee_recipient, &env.mint_b, &spl_token::ID);
+    assert!(env.account(&fee_receiver).await.is_none());
+    let ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    let token_b_receiver = env.initializer_ata(&env.mint_b);
+    env.taker_ix_result(ix).await.unwrap();
+
+    assert_eq!(env.token_balance(&fee_receiver).await, EXPECTED / 100);
+    assert_eq!(
+        env.token_balance(&token_b_receiver).await,
+        EXPECTED - EXPECTED / 100
+    );
+}
+
+#[tokio::test]
+async fn allowlisted_taker_can_exchange() {
+    let mut env = Env::new().await;
+    let other = Pubkey::new_unique();
+    let root = hash_pair(&taker_leaf(&env.taker.pubkey()), &taker_leaf(&other));
+    let mut ix = env.init_ix(1, None);
+    ix.data = EscrowInstruction::InitEscrow {
+        amount_to_trade: DEPOSIT,
+        amount_expected: EXPECTED,
+        seed: 1,
+        expiry: None,
+        native_side: NativeSide::None,
+        allowed_taker: None,
+        taker_merkle_root: Some(root),
+    }
+    .pack();
+    env.init_ix_result(ix).await.unwrap();
+
+    let proof = vec![taker_leaf(&other)];
+    env.taker_ix_result(env.exchange_ix(1, DEPOSIT, proof))
+        .await
+        .unwrap();
+    assert!(env.account(&env.escrow(1)).await.is_none());
+}
+
+#[tokio::test]
+async fn migrate_escrow_upgrades_a_v1_escrow() {
+    let mut env = Env::new().await;
+    let seed = 7;
+    let (escrow, bump) = find_escrow_address(&env.initializer.pubkey(), seed);
+    let state = EscrowState {
+        is_initialized: true,
+        initializer_pubkey: env.initializer.pubkey(),
+        mint_a: env.mint_a,
+        mint_b: env.mint_b,
+        expected_amount: EXPECTED,
+        bump,
+        seed,
+        ..EscrowState::default()
+    };
+    env.set_account(&escrow, &id(), pack_v1(&state));
+    let mint_a = env.mint_a;
+    env.mint_to(&mint_a, &escrow, DEPOSIT).await;
+
+    let result = env
+        .taker_ix_result(env.exchange_ix(seed, DEPOSIT, vec![]))
+        .await;
+    assert_error(result, EscrowError::EscrowNeedsMigration);
+
+    let initializer = env.initializer.pubkey();
+    let mut ix = instruction::migrate_escrow(&id(), &initializer, &mint_a, &spl_token::ID, seed);
+    ix.accounts[2].pubkey = env.initializer_ata(&mint_a);
+    assert_error(env.init_ix_result(ix).await, EscrowError::VaultKeyMismatch);
+
+    let ix = instruction::migrate_escrow(&id(), &initializer, &mint_a, &spl_token::ID, seed);
+    env.init_ix_result(ix).await.unwrap();
+    let account = env.account(&escrow).await.unwrap();
+    assert_eq!(account.data.len(), EscrowState::LEN);
+    let migrated = env.escrow_state(seed).await;
+    assert_eq!(migrated.deposit_amount, DEPOSIT);
+    assert_eq!(migrated.remaining_amount, DEPOSIT);
+    assert_eq!(migrated.expected_amount, EXPECTED);
+    assert_eq!(migrated.expiry, None);
+    assert_eq!(migrated.native_side, NativeSide::None);
+    assert_eq!(migrated.allowed_taker, None);
+    assert_eq!(migrated.taker_merkle_root, None);
+
+    let ix = instruction::migrate_escrow(&id(), &initializer, &mint_a, &spl_token::ID, seed);
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::EscrowAlreadyMigrated,
+    );
+
+    env.taker_ix_result(env.exchange_ix(seed, DEPOSIT, vec![]))
+        .await
+        .unwrap();
+    assert!(env.account(&escrow).await.is_none());
+}
+
+#[tokio::test]
+async fn admin_handover() {
+    let mut env = Env::new().await;
+    let admin = Keypair::new();
+    let new_admin = Keypair::new();
+    let fee_recipient = Pubkey::new_unique();
+    env.init_config(&admin, &fee_recipient, 0, FeeSide::B).await;
+
+    let ix = instruction::accept_admin(&id(), &new_admin.pubkey());
+    assert_error(
+        env.process(&[ix], &[&new_admin]).await,
+        EscrowError::NoPendingAdmin,
+    );
+
+    let ix = instruction::transfer_admin(&id(), &admin.pubkey(), &new_admin.pubkey());
+    env.process(&[ix], &[&admin]).await.unwrap();
+    let ix = instruction::accept_admin(&id(), &admin.pubkey());
+    assert_error(
+        env.process(&[ix], &[&admin]).await,
+        EscrowError::PendingAdminMismatch,
+    );
+    let ix = instruction::accept_admin(&id(), &new_admin.pubkey());
+    env.process(&[ix], &[&new_admin]).await.unwrap();
+
+    let config = env.account(&find_config_address().0).await.unwrap();
+    let config = Config::unpack_from_slice(&config.data).unwrap();
+    assert_eq!(config.admin, new_admin.pubkey());
+    assert_eq!(config.pending_admin, None);
+
+    let ix = instruction::set_paused(&id(), &admin.pubkey(), true);
+    assert_error(
+        env.process(&[ix], &[&admin]).await,
+        EscrowError::AdminMismatch,
+    );
+}
+
+#[tokio::test]
+async fn settle_swaps_the_deposit_for_the_escrow() {
+    let mut env = Env::new().await;
+    let (mint_a, mint_b) = (env.mint_a, env.mint_b);
+    let (initializer, taker) = (env.initializer.pubkey(), env.taker.pubkey());
+    env.mint_to(&mint_a, &initializer, DEPOSIT).await;
+    env.init(1, None).await;
+    env.init(2, None).await;
+
+    for seed in [1, 2] {
+        env.taker_ix_result(env.deposit_ix(seed, vec![]))
+            .await
+            .unwrap();
+        let deposit = env.deposit_state(seed).await;
+        assert_eq!(deposit.escrow, env.escrow(seed));
+        assert_eq!(deposit.counterparty, taker);
+        assert_eq!((deposit.amount_a, deposit.amount_b), (DEPOSIT, EXPECTED));
+        assert_eq!(env.token_balance(&env.deposit_vault(seed)).await, EXPECTED);
+    }
+
+    // either side can settle
+    let ix = env.settle_ix(1, &initializer);
+    env.init_ix_result(ix).await.unwrap();
+    let ix = env.settle_ix(2, &taker);
+    env.taker_ix_result(ix).await.unwrap();
+
+    let initializer_b = env.initializer_ata(&mint_b);
+    assert_eq!(env.token_balance(&initializer_b).await, 2 * EXPECTED);
+    let (taker_a, taker_b) = (env.taker_ata(&mint_a), env.taker_ata(&mint_b));
+    assert_eq!(env.token_balance(&taker_a).await, 2 * DEPOSIT);
+    assert_eq!(env.token_balance(&taker_b).await, TAKER_B - 2 * EXPECTED);
+    for seed in [1, 2] {
+        for key in [
+            env.escrow(seed),
+            env.vault(seed),
+            env.deposit(seed),
+            env.deposit_vault(seed),
+        ] {
+            assert!(env.account(&key).await.is_none());
+        }
+    }
+}
+
+#[tokio::test]
+async fn settle_pays_transfer_fees_of_both_hops_on_top() {
+    // 1% on both mints
+    let mut env = Env::with_transfer_fee(100).await;
+    let (mint_a, mint_b) = (env.mint_a, env.mint_b);
+    let initializer = env.initializer.pubkey();
+    env.init(1, None).await;
+    let deposit_amount = env.escrow_state(1).await.deposit_amount;
+
+    env.taker_ix_result(env.deposit_ix(1, vec![]))
+        .await
+        .unwrap();
+    assert_eq!(env.deposit_state(1).await.amount_b, EXPECTED);
+    // the vault holds the fee of paying the initializer, the counterparty paying the fee of the deposit too
+    let deposit_vault = env.deposit_vault(1);
+    assert_eq!(env.token_balance(&deposit_vault).await, EXPECTED + 51);
+    let taker_b = env.taker_ata(&mint_b);
+    assert_eq!(
+        env.token_balance(&taker_b).await,
+        TAKER_B - EXPECTED - 51 - 52
+    );
+
+    let ix = env.settle_ix(1, &initializer);
+    env.init_ix_result(ix).await.unwrap();
+    // the initializer gets exactly the expected amount
+    let initializer_b = env.initializer_ata(&mint_b);
+    assert_eq!(env.token_balance(&initializer_b).await, EXPECTED);
+    let taker_a = env.taker_ata(&mint_a);
+    assert_eq!(env.token_balance(&taker_a).await, deposit_amount - 10);
+    for key in [env.escrow(1), env.vault(1), env.deposit(1), deposit_vault] {
+        assert!(env.account(&key).await.is_none());
+    }
+    // the fee the deposit vault withheld when funded is harvested into the mint so it can be closed
+    let mint_b = env.account(&mint_b).await.unwrap();
+    let mint_b = StateWithExtensions::<Mint>::unpack(&mint_b.data).unwrap();
+    let withheld: u64 = mint_b
+        .get_extension::<TransferFeeConfig>()
+        .unwrap()
+        .withheld_amount
+        .into();
+    assert_eq!(withheld, 52);
+}
+
+#[tokio::test]
+async fn deposit_can_be_withdrawn_before_settlement() {
+    let mut env = Env::new().await;
+    let mint_b = env.mint_b;
+    let taker_b = env.taker_ata(&mint_b);
+    let taker_lamports = env.lamports(&env.taker.pubkey()).await;
+    env.init(1, None).await;
+
+    env.taker_ix_result(env.deposit_ix(1, vec![]))
+        .await
+        .unwrap();
+    assert_eq!(env.token_balance(&taker_b).await, TAKER_B - EXPECTED);
+    env.taker_ix_result(env.withdraw_deposit_ix(1))
+        .await
+        .unwrap();
+    assert_eq!(env.token_balance(&taker_b).await, TAKER_B);
+    assert_eq!(env.lamports(&env.taker.pubkey()).await, taker_lamports);
+    assert!(env.account(&env.deposit(1)).await.is_none());
+    assert!(env.account(&env.deposit_vault(1)).await.is_none());
+
+    // a deposit outlives its escrow, the initializer withdrawing by cancelling
+    env.taker_ix_result(env.deposit_ix(1, vec![]))
+        .await
+        .unwrap();
+    env.init_ix_result(env.cancel_ix(1)).await.unwrap();
+    let initializer_a = env.initializer_ata(&env.mint_a);
+    assert_eq!(env.token_balance(&initializer_a).await, DEPOSIT);
+    let ix = env.settle_ix(1, &env.taker.pubkey());
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::InvalidAccountType,
+    );
+    let taker = env.taker.pubkey();
+    let refund_receiver = env.create_token_account(&mint_b, &taker).await;
+    let mut ix = env.withdraw_deposit_ix(1);
+    ix.accounts[3].pubkey = refund_receiver;
+    env.taker_ix_result(ix).await.unwrap();
+    assert_eq!(env.token_balance(&refund_receiver).await, EXPECTED);
+}
+
+#[tokio::test]
+async fn exchange_minimum_is_net_of_the_fee_on_token_a() {
+    let mut env = Env::new().await;
+    let admin = Keypair::new();
+    let fee_recipient = Pubkey::new_unique();
+    env.init_config(&admin, &fee_recipient, 100, FeeSide::A)
+        .await;
+    let mint_a = env.mint_a;
+    env.init(1, None).await;
+
+    let exchange_ix = |env: &Env, min_amount_a| {
+        let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+        ix.data = EscrowInstruction::Exchange {
+            amount: EXPECTED,
+            fill_amount: DEPOSIT,
+            min_amount_a,
+            proof: vec![],
+        }
+        .pack();
+        ix
+    };
+    // the vault holds the whole deposit but the taker only receives it minus the fee
+    let ix = exchange_ix(&env, DEPOSIT);
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::AmountABelowMinimum,
+    );
+    let ix = exchange_ix(&env, DEPOSIT - DEPOSIT / 100);
+    env.taker_ix_result(ix).await.unwrap();
+    let taker_a = env.taker_ata(&mint_a);
+    assert_eq!(env.token_balance(&taker_a).await, DEPOSIT - DEPOSIT / 100);
+}
+
+#[tokio::test]
+async fn settle_pays_the_protocol_fee() {
+    let mut env = Env::new().await;
+    let admin = Keypair::new();
+    let fee_recipient = Pubkey::new_unique();
+    env.init_config(&admin, &fee_recipient, 100, FeeSide::A)
+        .await;
+    let mint_a = env.mint_a;
+    env.init(1, None).await;
+    env.taker_ix_result(env.deposit_ix(1, vec![]))
+        .await
+        .unwrap();
+
+    // the settler creates the fee receiver
+    let fee_receiver = token_account_address(&fee_recipient, &mint_a, &spl_token::ID);
+    assert!(env.account(&fee_receiver).await.is_none());
+    let ix = env.settle_ix(1, &env.taker.pubkey());
+    env.taker_ix_result(ix).await.unwrap();
+
+    assert_eq!(env.token_balance(&fee_receiver).await, DEPOSIT / 100);
+    let taker_a = env.taker_ata(&mint_a);
+    assert_eq!(env.token_balance(&taker_a).await, DEPOSIT - DEPOSIT / 100);
+}
+
+// instruction decoding
+
+#[tokio::test]
+async fn malformed_instruction_data() {
+    let mut env = Env::new().await;
+    let mut ix = env.init_ix(1, None);
+    for (data, error) in [
+        (vec![], EscrowError::InvalidInstructionType),
+        (vec![99], EscrowError::InvalidInstructionType),
+        (vec![7, 2], EscrowError::InvalidInstructionData),
+        (vec![0, 1, 2], EscrowError::InstructionDataTooShort),
+        (vec![2, 0], EscrowError::InstructionDataTooLong),
+    ] {
+        ix.data = data;
+        assert_error(env.init_ix_result(ix.clone()).await, error);
+    }
+}
+
+// account list
+
+#[tokio::test]
+async fn wrong_number_of_accounts() {
+    let mut env = Env::new().await;
+    env.init(1, None).await;
+
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts.truncate(12);
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::NotEnoughAccountKeys,
+    );
+
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts.push(ix.accounts[0].clone());
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::TooMuchAccountKeys,
+    );
+
+    let mut ix = env.cancel_ix(1);
+    ix.accounts.pop();
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::NotEnoughAccountKeys,
+    );
+}
+
+#[tokio::test]
+async fn missing_signature_and_read_only_accounts() {
+    let mut env = Env::new().await;
+    env.init(1, None).await;
+
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts[0].is_signer = false;
+    let result = env.process(&[ix], &[]).await;
+    assert_error(result, EscrowError::InvalidSigner);
+
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts[3].is_writable = false;
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::AccountNotWritable,
+    );
+}
+
+/// Marks every occurrence of `mint` in the instruction read-only
+fn read_only_mint(mut ix: Instruction, mint: &Pubkey) -> Instruction {
+    for account in ix.accounts.iter_mut().filter(|a| a.pubkey == *mint) {
+        account.is_writable = false;
+    }
+    ix
+}
+
+#[tokio::test]
+async fn mints_are_write_locked_only_with_a_transfer_fee() {
+    // a mint without transfer fee stays read-only
+    let mut env = Env::new().await;
+    env.init(1, None).await;
+    let mint_a = env.mint_a;
+    let ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    assert!(ix
+        .accounts
+        .iter()
+        .all(|a| a.pubkey != mint_a || !a.is_writable));
+    env.taker_ix_result(ix).await.unwrap();
+
+    // so does a token-2022 mint without the extension, though the builders lock it
+    let mut env = Env::with_token_program(spl_token_2022::ID).await;
+    env.init(1, None).await;
+    let mint_a = env.mint_a;
+    let ix = read_only_mint(env.cancel_ix(1), &mint_a);
+    env.init_ix_result(ix).await.unwrap();
+
+    // mint A receives the fees withheld by the vault when it is closed
+    let mut env = Env::with_transfer_fee(100).await;
+    env.init(1, None).await;
+    let mint_a = env.mint_a;
+    let ix = read_only_mint(env.exchange_ix(1, DEPOSIT - 10, vec![]), &mint_a);
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::AccountNotWritable,
+    );
+    let ix = read_only_mint(env.cancel_ix(1), &mint_a);
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::AccountNotWritable,
+    );
+    let ix = read_only_mint(env.expire_ix(1), &mint_a);
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::AccountNotWritable,
+    );
+}
+
+#[tokio::test]
+async fn wrong_programs() {
+    let mut env = Env::new().await;
+
+    let mut ix = env.init_ix(1, None);
+    ix.accounts[6].pubkey = spl_token_2022::ID;
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::InvalidTokenProgram,
+    );
+
+    let mut ix = env.init_ix(1, None);
+    ix.accounts[7].pubkey = spl_token::ID;
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::InvalidAssociatedTokenProgram,
+    );
+
+    let mut ix = env.init_ix(1, None);
+    ix.accounts[8].pubkey = spl_token::ID;
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::InvalidSystemProgram,
+    );
+}
+
+// InitEscrow
+
+#[tokio::test]
+async fn init_escrow_rejects_bad_parameters() {
+    let mut env = Env::new().await;
+
+    let mut ix = env.init_ix(1, None);
+    ix.data = EscrowInstruction::InitEscrow {
+        amount_to_trade: 0,
+        amount_expected: EXPECTED,
+        seed: 1,
+        expiry: None,
+        native_side: NativeSide::None,
+        allowed_taker: None,
+        taker_merkle_root: None,
+    }
+    .pack();
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::InvalidTradeAmount,
+    );
+    let mut ix = env.init_ix(1, None);
+    ix.data = EscrowInstruction::InitEscrow {
+        amount_to_trade: DEPOSIT,
+        amount_expected: 0,
+        seed: 1,
+        expiry: None,
+        native_side: NativeSide::None,
+        allowed_taker: None,
+        taker_merkle_root: None,
+    }
+    .pack();
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::InvalidTradeAmount,
+    );
+
+    let now = env.now().await;
+    let ix = env.init_ix(1, Some(now));
+    assert_error(env.init_ix_result(ix).await, EscrowError::EscrowExpired);
+
+    let mut ix = env.init_ix(1, None);
+    ix.accounts[1].pubkey = env.escrow(2);
+    assert_error(env.init_ix_result(ix).await, EscrowError::EscrowKeyMismatch);
+
+    let mut ix = env.init_ix(1, None);
+    ix.accounts[2].pubkey = env.initializer_ata(&env.mint_a);
+    assert_error(env.init_ix_result(ix).await, EscrowError::VaultKeyMismatch);
+
+    // the CPI error of the token program is returned as is, here `InsufficientFunds`
+    let mut ix = env.init_ix(1, None);
+    ix.data = EscrowInstruction::InitEscrow {
+        amount_to_trade: DEPOSIT + 1,
+        amount_expected: EXPECTED,
+        seed: 1,
+        expiry: None,
+        native_side: NativeSide::None,
+        allowed_taker: None,
+        taker_merkle_root: None,
+    }
+    .pack();
+    assert_custom_error(
+        env.init_ix_result(ix).await,
+        spl_token::error::TokenError::InsufficientFunds as u32,
+    );
+
+    env.init(1, None).await;
+    let ix = env.init_ix(1, None);
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::EscrowAlreadyInitialized,
+    );
+}
+
+#[tokio::test]
+async fn init_escrow_rejects_bad_token_accounts() {
+    let mut env = Env::new().await;
+
+    // a mint where a token account is expected
+    let mut ix = env.init_ix(1, None);
+    ix.accounts[3].pubkey = env.mint_b;
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::DeserializeTokenAccountError,
+    );
+
+    // a token account where a mint is expected
+    let mut ix = env.init_ix(1, None);
+    ix.accounts[5].pubkey = env.initializer_ata(&env.mint_a);
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::DeserializeMintAccountError,
+    );
+
+    // token-shaped accounts owned by another program
+    let token_data = env
+        .account(&env.initializer_ata(&env.mint_a))
+        .await
+        .unwrap()
+        .data;
+    let fake_token_account = Pubkey::new_unique();
+    env.set_account(&fake_token_account, &system_program::ID, token_data);
+    let mut ix = env.init_ix(1, None);
+    ix.accounts[3].pubkey = fake_token_account;
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::InvalidTokenAccountOwner,
+    );
+
+    let mint_b = env.mint_b;
+    let mint_data = env.account(&mint_b).await.unwrap().data;
+    let fake_mint = Pubkey::new_unique();
+    env.set_account(&fake_mint, &system_program::ID, mint_data);
+    let mut ix = env.init_ix(1, None);
+    ix.accounts[5].pubkey = fake_mint;
+    assert_error(env.init_ix_result(ix).await, EscrowError::InvalidMintOwner);
+}
+
+#[tokio::test]
+async fn init_escrow_checks_the_token_a_account() {
+    let mut env = Env::new().await;
+    let (mint_a, mint_b, taker) = (env.mint_a, env.mint_b, env.taker.pubkey());
+
+    let taker_a = env.mint_to(&mint_a, &taker, 0).await;
+    let mut ix = env.init_ix(1, None);
+    ix.accounts[3].pubkey = taker_a;
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::TokenAFounderOwnerMismatch,
+    );
+
+    let initializer = env.initializer.pubkey();
+    let initializer_b = env.mint_to(&mint_b, &initializer, 0).await;
+    let mut ix = env.init_ix(1, None);
+    ix.accounts[3].pubkey = initializer_b;
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::TokenAFounderMintMismatch,
+    );
+
+    // any token account of the initializer for mint A can fund the escrow
+    let founder = env.create_token_account(&mint_a, &initializer).await;
+    let initializer_a = env.initializer_ata(&mint_a);
+    let transfer = spl_token::instruction::transfer(
+        &spl_token::ID,
+        &initializer_a,
+        &founder,
+        &initializer,
+        &[],
+        DEPOSIT,
+    )
+    .unwrap();
+    let mut ix = env.init_ix(1, None);
+    ix.accounts[3].pubkey = founder;
+    let signer = env.initializer.insecure_clone();
+    env.process(&[transfer, ix], &[&signer]).await.unwrap();
+    assert_eq!(env.token_balance(&env.vault(1)).await, DEPOSIT);
+}
+
+#[tokio::test]
+async fn init_escrow_rejects_bad_native_accounts() {
+    let mut env = Env::new().await;
+    let initializer = env.initializer.pubkey();
+    let init_native = |mint_a: &Pubkey, founder: &Pubkey| {
+        instruction::init_escrow(
+            &id(),
+            &initializer,
+            mint_a,
+            &env.mint_b,
+            founder,
+            &system_program::ID,
+            DEPOSIT,
+            EXPECTED,
+            1,
+            None,
+            NativeSide::A,
+            None,
+            None,
+        )
+    };
+    let mut ix = init_native(&native_mint::ID, &initializer);
+    ix.accounts[4].pubkey = env.mint_a;
+    let ix_wrong_founder = init_native(&native_mint::ID, &env.taker.pubkey());
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::NativeMintMismatch,
+    );
+    assert_error(
+        env.init_ix_result(ix_wrong_founder).await,
+        EscrowError::NativeAccountMismatch,
+    );
+}
+
+// Exchange
+
+#[tokio::test]
+async fn exchange_rejects_wrong_terms() {
+    let mut env = Env::new().await;
+    env.init(1, None).await;
+
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.data = EscrowInstruction::Exchange {
+        amount: EXPECTED - 1,
+        fill_amount: DEPOSIT,
+        min_amount_a: 0,
+        proof: vec![],
+    }
+    .pack();
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::ExpectedAmountMismatch,
+    );
+
+    let ix = env.exchange_ix(1, 0, vec![]);
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::InvalidFillAmount,
+    );
+
+    let ix = env.exchange_ix(1, DEPOSIT + 1, vec![]);
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::InvalidFillAmount,
+    );
+
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.data = EscrowInstruction::Exchange {
+        amount: EXPECTED,
+        fill_amount: DEPOSIT,
+        min_amount_a: DEPOSIT + 1,
+        proof: vec![],
+    }
+    .pack();
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::AmountABelowMinimum,
+    );
+}
+
+#[tokio::test]
+async fn exchange_rejects_wrong_accounts() {
+    let mut env = Env::new().await;
+    env.init(1, None).await;
+
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts[1].pubkey = env.taker.pubkey();
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::InitializerMismatch,
+    );
+
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts[3].pubkey = env.initializer_ata(&env.mint_a);
+    assert_error(env.taker_ix_result(ix).await, EscrowError::VaultKeyMismatch);
+
+    // a vault of another mint, created as the ATA of the escrow so the vault check passes
+    let escrow = env.escrow(1);
+    let mint_c = env.create_mint(&spl_token::ID).await;
+    let vault_c = env.mint_to(&mint_c, &escrow, 0).await;
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts[3].pubkey = vault_c;
+    ix.accounts[7].pubkey = mint_c;
+    assert_error(env.taker_ix_result(ix).await, EscrowError::MintAMismatch);
+
+    let taker = env.taker.pubkey();
+    let taker_c = env.mint_to(&mint_c, &taker, 0).await;
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts[6].pubkey = taker_c;
+    ix.accounts[8].pubkey = mint_c;
+    assert_error(env.taker_ix_result(ix).await, EscrowError::MintBMismatch);
+}
+
+#[tokio::test]
+async fn exchange_checks_the_taker_token_accounts() {
+    let mut env = Env::new().await;
+    env.init(1, None).await;
+    let (mint_a, mint_b) = (env.mint_a, env.mint_b);
+    let (initializer, taker) = (env.initializer.pubkey(), env.taker.pubkey());
+
+    // a missing receiver can only be the taker's ATA, which the exchange creates
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts[4].pubkey = Pubkey::new_unique();
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::TokenAReceiverMismatch,
+    );
+
+    let initializer_a = env.initializer_ata(&mint_a);
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts[4].pubkey = initializer_a;
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::TokenAReceiverOwnerMismatch,
+    );
+
+    let taker_b = env.taker_ata(&mint_b);
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts[4].pubkey = taker_b;
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::TokenAReceiverMintMismatch,
+    );
+
+    let initializer_b = env.mint_to(&mint_b, &initializer, 0).await;
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts[6].pubkey = initializer_b;
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::TokenBFounderOwnerMismatch,
+    );
+
+    let taker_a = env.mint_to(&mint_a, &taker, 0).await;
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts[6].pubkey = taker_a;
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::TokenBFounderMintMismatch,
+    );
+
+    // token accounts of the taker that are not its ATAs are accepted
+    let receiver = env.create_token_account(&mint_a, &taker).await;
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts[4].pubkey = receiver;
+    env.taker_ix_result(ix).await.unwrap();
+    assert_eq!(env.token_balance(&receiver).await, DEPOSIT);
+    assert_eq!(env.token_balance(&taker_a).await, 0);
+}
+
+#[tokio::test]
+async fn exchange_cannot_redirect_the_payment() {
+    let mut env = Env::new().await;
+    env.init(1, None).await;
+    let (mint_b, escrow) = (env.mint_b, env.escrow(1));
+    let taker_b = env.taker_ata(&mint_b);
+    // the ATA of the escrow, where token B used to be paid
+    let escrow_b = env.mint_to(&mint_b, &escrow, 0).await;
+
+    for receiver in [taker_b, escrow_b, env.initializer.pubkey()] {
+        let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+        ix.accounts[5].pubkey = receiver;
+        assert_error(
+            env.taker_ix_result(ix).await,
+            EscrowError::TokenBReceiverMismatch,
+        );
+    }
+    assert_eq!(env.token_balance(&taker_b).await, TAKER_B);
+    assert_eq!(env.token_balance(&escrow_b).await, 0);
+
+    env.taker_ix_result(env.exchange_ix(1, DEPOSIT, vec![]))
+        .await
+        .unwrap();
+    let initializer_b = env.initializer_ata(&mint_b);
+    assert_eq!(env.token_balance(&initializer_b).await, EXPECTED);
+    assert_eq!(env.token_balance(&taker_b).await, TAKER_B - EXPECTED);
+    assert_eq!(env.token_balance(&escrow_b).await, 0);
+}
+
+#[tokio::test]
+async fn exchange_cannot_redirect_a_native_sol_payment() {
+    let mut env = Env::new().await;
+    let (initializer, taker) = (env.initializer.pubkey(), env.taker.pubkey());
+    let ix = instruction::init_escrow(
+        &id(),
+        &initializer,
+        &env.mint_a,
+        &native_mint::ID,
+        &env.initializer_ata(&env.mint_a),
+        &env.token_program,
+        DEPOSIT,
+        EXPECTED,
+        1,
+        None,
+        NativeSide::B,
+        None,
+        None,
+    );
+    env.init_ix_result(ix).await.unwrap();
+    let exchange = instruction::exchange(
+        &id(),
+        &taker,
+        &initializer,
+        &env.mint_a,
+        &native_mint::ID,
+        &taker,
+        &env.token_program,
+        &system_program::ID,
+        1,
+        EXPECTED,
+        DEPOSIT,
+        0,
+        vec![],
+        &Pubkey::default(),
+        FeeSide::B,
+    );
+    assert_eq!(exchange.accounts[5].pubkey, initializer);
+
+    let mut ix = exchange.clone();
+    ix.accounts[5].pubkey = taker;
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::TokenBReceiverMismatch,
+    );
+
+    let (escrow, vault) = (env.escrow(1), env.vault(1));
+    let rent = env.lamports(&escrow).await + env.lamports(&vault).await;
+    let initializer_lamports = env.lamports(&initializer).await;
+    env.taker_ix_result(exchange).await.unwrap();
+    assert_eq!(
+        env.lamports(&initializer).await,
+        initializer_lamports + EXPECTED + rent
+    );
+}
+
+#[tokio::test]
+async fn exchange_rejects_an_expired_escrow() {
+    let mut env = Env::new().await;
+    let now = env.now().await;
+    env.init(1, Some(now + 100)).await;
+    env.set_unix_timestamp(now + 100).await;
+
+    let ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    assert_error(env.taker_ix_result(ix).await, EscrowError::EscrowExpired);
+    let ix = env.cancel_ix(1);
+    env.init_ix_result(ix).await.unwrap();
+}
+
+#[tokio::test]
+async fn exchange_rejects_dust_fills() {
+    let mut env = Env::new().await;
+    let mut ix = env.init_ix(1, None);
+    ix.data = EscrowInstruction::InitEscrow {
+        amount_to_trade: DEPOSIT,
+        amount_expected: 1,
+        seed: 1,
+        expiry: None,
+        native_side: NativeSide::None,
+        allowed_taker: None,
+        taker_merkle_root: None,
+    }
+    .pack();
+    env.init_ix_result(ix).await.unwrap();
+
+    let exchange = |fill_amount| {
+        let mut ix = env.exchange_ix(1, fill_amount, vec![]);
+        ix.data = EscrowInstruction::Exchange {
+            amount: 1,
+            fill_amount,
+            min_amount_a: 0,
+            proof: vec![],
+        }
+        .pack();
+        ix
+    };
+    // fills are priced at zero and refused until the final one, which pays for the whole trade
+    let (dust, almost_all, all) = (exchange(1), exchange(DEPOSIT - 1), exchange(DEPOSIT));
+    assert_error(env.taker_ix_result(dust).await, EscrowError::FillTooSmall);
+    assert_error(
+        env.taker_ix_result(almost_all).await,
+        EscrowError::FillTooSmall,
+    );
+    env.taker_ix_result(all).await.unwrap();
+    let initializer_b = env.initializer_ata(&env.mint_b);
+    assert_eq!(env.token_balance(&initializer_b).await, 1);
+}
+
+#[tokio::test]
+async fn exchange_enforces_private_escrows() {
+    let mut env = Env::new().await;
+    let make_init = |seed, allowed_taker, taker_merkle_root| {
+        let mut ix = env.init_ix(seed, None);
+        ix.data = EscrowInstruction::InitEscrow {
+            amount_to_trade: DEPOSIT / 2,
+            amount_expected: EXPECTED,
+            seed,
+            expiry: None,
+            native_side: NativeSide::None,
+            allowed_taker,
+            taker_merkle_root,
+        }
+        .pack();
+        ix
+    };
+    let private = make_init(1, Some(Pubkey::new_unique()), None);
+    let listed = make_init(2, None, Some([7; 32]));
+    env.init_ix_result(private).await.unwrap();
+    env.init_ix_result(listed).await.unwrap();
+
+    let ix = env.exchange_ix(1, DEPOSIT / 2, vec![]);
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::UnauthorizedTaker,
+    );
+    let ix = env.exchange_ix(2, DEPOSIT / 2, vec![[1; 32]]);
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::InvalidTakerProof,
+    );
+}
+
+// escrow account
+
+#[tokio::test]
+async fn escrow_account_must_be_a_valid_escrow() {
+    let mut env = Env::new().await;
+    env.init(1, None).await;
+    let escrow = env.escrow(1);
+    let state = env.escrow_state(1).await;
+
+    // garbage behind a valid header
+    let mut data = pack_escrow(EscrowState::default());
+    data[9] = 2;
+    env.set_account(&escrow, &id(), data);
+    let ix = env.cancel_ix(1);
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::DeserializeEscrowAccountError,
+    );
+
+    // another account type of this program
+    let mut data = vec![0; Config::LEN];
+    Config::pack(Config::default(), &mut data).unwrap();
+    env.set_account(&escrow, &id(), data);
+    let ix = env.cancel_ix(1);
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::InvalidAccountType,
+    );
+
+    // a valid escrow owned by another program
+    env.set_account(&escrow, &spl_token::ID, pack_escrow(state.clone()));
+    let ix = env.cancel_ix(1);
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::InvalidEscrowOwner,
+    );
+
+    // an escrow not initialized
+    let uninitialized = EscrowState {
+        is_initialized: false,
+        ..state.clone()
+    };
+    env.set_account(&escrow, &id(), pack_escrow(uninitialized));
+    let ix = env.cancel_ix(1);
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::EscrowNotInitialized,
+    );
+
+    // an escrow that is not at its own address
+    let moved = EscrowState {
+        seed: 2,
+        ..state.clone()
+    };
+    env.set_account(&escrow, &id(), pack_escrow(moved));
+    let ix = env.cancel_ix(1);
+    assert_error(env.init_ix_result(ix).await, EscrowError::EscrowKeyMismatch);
+}
+
+// Cancel and Expire
+
+#[tokio::test]
+async fn cancel_rejects_wrong_accounts() {
+    let mut env = Env::new().await;
+    env.init(1, None).await;
+
+    let mut ix = env.cancel_ix(1);
+    ix.accounts[0].pubkey = env.taker.pubkey();
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::InitializerMismatch,
+    );
+
+    let mut ix = env.cancel_ix(1);
+    ix.accounts[2].pubkey = env.initializer_ata(&env.mint_a);
+    assert_error(env.init_ix_result(ix).await, EscrowError::VaultKeyMismatch);
+}
+
+#[tokio::test]
+async fn expire_rejects_a_live_escrow() {
+    let mut env = Env::new().await;
+    let now = env.now().await;
+    env.init(1, Some(now + 100)).await;
+
+    let ix = env.expire_ix(1);
+    assert_error(env.taker_ix_result(ix).await, EscrowError::EscrowNotExpired);
+
+    env.set_unix_timestamp(now + 100).await;
+    let mut ix = env.expire_ix(1);
+    ix.accounts[4].pubkey = env.taker_ata(&env.mint_a);
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::TokenAReceiverMismatch,
+    );
+}
+
+#[tokio::test]
+async fn closed_escrows_cannot_be_revived() {
+    let mut env = Env::new().await;
+    let (mint_a, initializer) = (env.mint_a, env.initializer.pubkey());
+    env.mint_to(&mint_a, &initializer, 2 * DEPOSIT).await;
+    let now = env.now().await;
+    env.init(1, None).await;
+    env.init(2, None).await;
+    env.init(3, Some(now + 100)).await;
+    env.set_unix_timestamp(now + 100).await;
+
+    let rent = Rent::default().minimum_balance(EscrowState::LEN);
+    let (taker, initializer) = (env.taker.insecure_clone(), env.initializer.insecure_clone());
+    let closes = [
+        (1, env.exchange_ix(1, DEPOSIT, vec![]), &taker),
+        (2, env.cancel_ix(2), &initializer),
+        (3, env.expire_ix(3), &taker),
+    ];
+    for (seed, close, signer) in closes {
+        // refund the rent to the closed escrow within the same transaction
+        let escrow = env.escrow(seed);
+        let refund = system_instruction::transfer(&signer.pubkey(), &escrow, rent);
+        env.process(&[close, refund], &[signer]).await.unwrap();
+
+        let account = env.account(&escrow).await.unwrap();
+        assert_eq!(account.owner, system_program::ID);
+        assert!(account.data.is_empty());
+        assert_eq!(account.lamports, rent);
+        let ix = env.cancel_ix(seed);
+        assert_error(
+            env.init_ix_result(ix).await,
+            EscrowError::InvalidAccountType,
+        );
+    }
+}
+
+// Deposit, Settle and WithdrawDeposit
+
+#[tokio::test]
+async fn deposit_rejects_bad_requests() {
+    let mut env = Env::new().await;
+    let now = env.now().await;
+    env.init(1, Some(now + 100)).await;
+
+    let mut ix = env.deposit_ix(1, vec![]);
+    ix.data = EscrowInstruction::Deposit {
+        amount: EXPECTED - 1,
+        proof: vec![],
+    }
+    .pack();
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::ExpectedAmountMismatch,
+    );
+
+    let mut ix = env.deposit_ix(1, vec![]);
+    ix.accounts[2].pubkey = Pubkey::new_unique();
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::DepositKeyMismatch,
+    );
+
+    let mut ix = env.deposit_ix(1, vec![]);
+    ix.accounts[3].pubkey = env.taker_ata(&env.mint_b);
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::DepositVaultMismatch,
+    );
+
+    env.taker_ix_result(env.deposit_ix(1, vec![]))
+        .await
+        .unwrap();
+    assert_error(
+        env.taker_ix_result(env.deposit_ix(1, vec![])).await,
+        EscrowError::DepositAlreadyInitialized,
+    );
+
+    env.set_unix_timestamp(now + 100).await;
+    let ix = env.settle_ix(1, &env.taker.pubkey());
+    assert_error(env.taker_ix_result(ix).await, EscrowError::EscrowExpired);
+}
+
+#[tokio::test]
+async fn deposit_rejects_native_escrows() {
+    let mut env = Env::new().await;
+    let initializer = env.initializer.pubkey();
+    let ix = instruction::init_escrow(
+        &id(),
+        &initializer,
+        &native_mint::ID,
+        &env.mint_b,
+        &initializer,
+        &system_program::ID,
+        DEPOSIT,
+        EXPECTED,
+        1,
+        None,
+        NativeSide::A,
+        None,
+        None,
+    );
+    env.init_ix_result(ix).await.unwrap();
+
+    assert_error(
+        env.taker_ix_result(env.deposit_ix(1, vec![])).await,
+        EscrowError::NativeDepositUnsupported,
+    );
+}
+
+#[tokio::test]
+async fn settle_rejects_outsiders_and_stale_deposits() {
+    let mut env = Env::new().await;
+    env.init(1, None).await;
+    env.taker_ix_result(env.deposit_ix(1, vec![]))
+        .await
+        .unwrap();
+
+    // the payer signs every transaction but is neither side of the trade
+    let payer = env.ctx.payer.pubkey();
+    let ix = env.settle_ix(1, &payer);
+    assert_error(
+        env.process(&[ix], &[]).await,
+        EscrowError::UnauthorizedSettler,
+    );
+
+    let mut ix = env.settle_ix(1, &env.initializer.pubkey());
+    ix.accounts[2].pubkey = env.initializer.pubkey();
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::CounterpartyMismatch,
+    );
+
+    let mut ix = env.withdraw_deposit_ix(1);
+    ix.accounts[0].pubkey = env.initializer.pubkey();
+    ix.accounts[3].pubkey = env.initializer_ata(&env.mint_a);
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::CounterpartyMismatch,
+    );
+
+    // a fill after the deposit leaves it paying for more than the escrow holds
+    let ix = env.exchange_ix(1, DEPOSIT / 2, vec![]);
+    env.taker_ix_result(ix).await.unwrap();
+    let ix = env.settle_ix(1, &env.taker.pubkey());
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::DepositTermsMismatch,
+    );
+}
+
+#[tokio::test]
+async fn deposit_account_must_be_a_valid_deposit() {
+    let mut env = Env::new().await;
+    let (mint_b, taker) = (env.mint_b, env.taker.pubkey());
+    let (deposit_key, bump) = find_deposit_address(&env.escrow(1));
+    env.mint_to(&mint_b, &deposit_key, 0).await;
+    let pack = |deposit: DepositState| {
+        let mut data = vec![0; DepositState::LEN];
+        DepositState::pack(deposit, &mut data).unwrap();
+        data
+    };
+    let deposit = DepositState {
+        is_initialized: true,
+        escrow: env.escrow(1),
+        counterparty: taker,
+        mint_b,
+        bump,
+        ..DepositState::default()
+    };
+    // a malformed deposit, its initialized flag being neither 0 nor 1
+    let mut malformed = pack(deposit);
+    malformed[8] = 2;
+    let cases = [
+        (
+            system_program::ID,
+            pack(deposit),
+            EscrowError::InvalidDepositOwner,
+        ),
+        (
+            id(),
+            pack(DepositState {
+                is_initialized: false,
+                ..deposit
+            }),
+            EscrowError::DepositNotInitialized,
+        ),
+        (
+            id(),
+            pack(DepositState {
+                bump: bump.wrapping_sub(1),
+                ..deposit
+            }),
+            EscrowError::DepositKeyMismatch,
+        ),
+        (id(), malformed, EscrowError::InvalidDepositState),
+    ];
+    for (owner, data, error) in cases {
+        env.set_account(&deposit_key, &owner, data);
+        assert_error(env.taker_ix_result(env.withdraw_deposit_ix(1)).await, error);
+    }
+}
+
+// config
+
+#[tokio::test]
+async fn config_management_errors() {
+    let mut env = Env::new().await;
+    let admin = Keypair::new();
+    env.airdrop(&admin.pubkey(), 1_000_000_000).await;
+    env.set_upgrade_authority(Some(&admin.pubkey()));
+    let fee_recipient = Pubkey::new_unique();
+
+    let ix = instruction::init_config(&id(), &admin.pubkey(), &fee_recipient, 1_001, FeeSide::B);
+    assert_error(
+        env.process(&[ix], &[&admin]).await,
+        EscrowError::InvalidFeeBasisPoints,
+    );
+
+    let mut ix = instruction::init_config(&id(), &admin.pubkey(), &fee_recipient, 0, FeeSide::B);
+    ix.accounts[1].pubkey = Pubkey::new_unique();
+    assert_error(
+        env.process(&[ix], &[&admin]).await,
+        EscrowError::ConfigKeyMismatch,
+    );
+
+    let ix = instruction::init_config(&id(), &admin.pubkey(), &fee_recipient, 0, FeeSide::B);
+    env.process(&[ix], &[&admin]).await.unwrap();
+    let ix = instruction::init_config(&id(), &admin.pubkey(), &fee_recipient, 1, FeeSide::B);
+    assert_error(
+        env.process(&[ix], &[&admin]).await,
+        EscrowError::ConfigAlreadyInitialized,
+    );
+
+    let ix = instruction::update_config(&id(), &admin.pubkey(), &fee_recipient, 1_001, FeeSide::A);
+    assert_error(
+        env.process(&[ix], &[&admin]).await,
+        EscrowError::InvalidFeeBasisPoints,
+    );
+
+    let intruder = env.taker.insecure_clone();
+    let ix = instruction::update_config(&id(), &intruder.pubkey(), &fee_recipient, 1, FeeSide::A);
+    assert_error(
+        env.process(&[ix], &[&intruder]).await,
+        EscrowError::AdminMismatch,
+    );
+}
+
+#[tokio::test]
+async fn only_the_upgrade_authority_can_create_the_config() {
+    let mut env = Env::new().await;
+    let authority = Pubkey::new_unique();
+    let intruder = env.taker.insecure_clone();
+    let fee_recipient = Pubkey::new_unique();
+    let init_config_ix =
+        || instruction::init_config(&id(), &intruder.pubkey(), &fee_recipient, 0, FeeSide::B);
+
+    // the program data account of an upgradeable program is created by the loader
+    assert_error(
+        env.process(&[init_config_ix()], &[&intruder]).await,
+        EscrowError::InvalidProgramData,
+    );
+
+    env.set_upgrade_authority(Some(&authority));
+    assert_error(
+        env.process(&[init_config_ix()], &[&intruder]).await,
+        EscrowError::UpgradeAuthorityMismatch,
+    );
+
+    // a look-alike of the program data account at another address
+    let fake_program_data = Pubkey::new_unique();
+    env.set_account(
+        &fake_program_data,
+        &bpf_loader_upgradeable::ID,
+        program_data_bytes(Some(&intruder.pubkey())),
+    );
+    let mut ix = init_config_ix();
+    ix.accounts[3].pubkey = fake_program_data;
+    assert_error(
+        env.process(&[ix], &[&intruder]).await,
+        EscrowError::InvalidProgramData,
+    );
+
+    // an immutable program can never get a config
+    env.set_upgrade_authority(None);
+    assert_error(
+        env.process(&[init_config_ix()], &[&intruder]).await,
+        EscrowError::UpgradeAuthorityMismatch,
+    );
+    assert!(env.account(&find_config_address().0).await.is_none());
+}
+
+#[tokio::test]
+async fn config_account_must_be_a_valid_config() {
+    let mut env = Env::new().await;
+    let (config_key, _) = find_config_address();
+
+    // an empty account that is not the config PDA
+    let mut ix = env.init_ix(1, None);
+    ix.accounts[9].pubkey = Pubkey::new_unique();
+    assert_error(env.init_ix_result(ix).await, EscrowError::ConfigKeyMismatch);
+
+    // a config owned by another program
+    let mut data = vec![0; Config::LEN];
+    Config::pack(Config::default(), &mut data).unwrap();
+    env.set_account(&config_key, &system_program::ID, data.clone());
+    let ix = env.init_ix(1, None);
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::InvalidConfigOwner,
+    );
+
+    // a malformed config, its fee side being out of range
+    data[8 + 32 + 32 + 2] = 7;
+    env.set_account(&config_key, &id(), data);
+    let ix = env.init_ix(1, None);
+    assert_error(
+        env.init_ix_result(ix).await,
+        EscrowError::InvalidConfigState,
+    );
+}
+
+#[tokio::test]
+async fn exchange_rejects_a_wrong_fee_receiver() {
+    let mut env = Env::new().await;
+    let admin = Keypair::new();
+    env.init_config(&admin, &Pubkey::new_unique(), 100, FeeSide::B)
+        .await;
+    env.init(1, None).await;
+
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts[14].pubkey = env.taker_ata(&env.mint_b);
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::FeeReceiverMismatch,
+    );
+    let mut ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    ix.accounts[15].pubkey = env.taker.pubkey();
+    assert_error(
+        env.taker_ix_result(ix).await,
+        EscrowError::FeeReceiverMismatch,
+    );
+}
+
+#[tokio::test]
+async fn paused_program_refuses_new_trades_but_not_refunds() {
+    let mut env = Env::new().await;
+    let admin = Keypair::new();
+    env.init_config(&admin, &Pubkey::new_unique(), 0, FeeSide::B)
+        .await;
+    let (mint_a, initializer) = (env.mint_a, env.initializer.pubkey());
+    env.mint_to(&mint_a, &initializer, DEPOSIT).await;
+    env.init(1, None).await;
+    env.init(3, None).await;
+    env.taker_ix_result(env.deposit_ix(1, vec![]))
+        .await
+        .unwrap();
+
+    let ix = instruction::set_paused(&id(), &admin.pubkey(), true);
+    env.process(&[ix], &[&admin]).await.unwrap();
+
+    let ix = env.init_ix(2, None);
+    assert_error(env.init_ix_result(ix).await, EscrowError::ProgramPaused);
+    let ix = env.exchange_ix(1, DEPOSIT, vec![]);
+    assert_error(env.taker_ix_result(ix).await, EscrowError::ProgramPaused);
+    let ix = env.deposit_ix(3, vec![]);
+    assert_error(env.taker_ix_result(ix).await, EscrowError::ProgramPaused);
+    let ix = env.settle_ix(1, &env.taker.pubkey());
+    assert_error(env.taker_ix_result(ix).await, EscrowError::ProgramPaused);
+
+    let ix = env.cancel_ix(1);
+    env.init_ix_result(ix).await.unwrap();
+    let ix = env.withdraw_deposit_ix(1);
+    env.taker_ix_result(ix).await.unwrap();
+}
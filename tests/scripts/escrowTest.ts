@@ -26,7 +26,16 @@ import {
   ASSOCIATED_TOKEN_PROGRAM_ID,
   syncNative,
 } from "@solana/spl-token";
-import { publicKey, struct, u64, u8 } from "@project-serum/borsh";
+import {
+  array,
+  i64,
+  option,
+  publicKey,
+  struct,
+  u64,
+  u8,
+  vec,
+} from "@project-serum/borsh";
 
 const keyPairPath = os.homedir() + "/.config/solana/G9.json";
 const PrivateKey = JSON.parse(fs.readFileSync(keyPairPath, "utf-8"));
@@ -53,10 +62,22 @@ const INIT_LAYOUT = struct([
   u64("amountToTrade"),
   u64("depositAmount"),
   u64("slot"),
+  option(i64(), "expiry"),
+  u8("nativeSide"),
+  option(publicKey(), "allowedTaker"),
+  option(array(u8(), 32), "takerMerkleRoot"),
+]);
+const EXCHANGE_LAYOUT = struct([
+  u8("instruction"),
+  u64("amountToTrade"),
+  u64("fillAmount"),
+  u64("minAmountA"),
+  vec(array(u8(), 32), "proof"),
 ]);
-const EXCHANGE_LAYOUT = struct([u8("instruction"), u64("amountToTrade")]);
 const U64_LAYOUT = struct([u64("u64")]);
 const ESCROW_STATE_LAYOUT = struct([
+  array(u8(), 8, "discriminator"),
+  u8("version"),
   u8("isInitialized"),
   publicKey("initializerPubkey"),
   publicKey("mintA"),
@@ -64,6 +85,12 @@ const ESCROW_STATE_LAYOUT = struct([
   u64("expectedAmount"),
   u8("bump"),
   u64("seed"),
+  option(i64(), "expiry"),
+  u64("depositAmount"),
+  u64("remainingAmount"),
+  u8("nativeSide"),
+  option(publicKey(), "allowedTaker"),
+  option(array(u8(), 32), "takerMerkleRoot"),
 ]);
 
 async function init() {
@@ -91,14 +118,18 @@ async function init() {
   );
 
   let sync = await syncNative(connection, wallet, tokenAATa);
-  let payload = Buffer.alloc(INIT_LAYOUT.span);
+  let payload = Buffer.alloc(1000);
   let slot = await connection.getSlot();
-  INIT_LAYOUT.encode(
+  let payloadLen = INIT_LAYOUT.encode(
     {
       instruction: new BN(0),
       amountToTrade: new BN(1000),
       depositAmount: new BN(100000000),
       slot: new BN(slot),
+      expiry: null,
+      nativeSide: 0,
+      allowedTaker: null,
+      takerMerkleRoot: null,
     },
     payload
   );
@@ -110,6 +141,10 @@ async function init() {
     ESCROW_PROGRAM_ID
   );
   let vaultAta = getAssociatedTokenAddressSync(NATIVE_MINT, pda, true);
+  let [configKey] = PublicKey.findProgramAddressSync(
+    [Buffer.from("config")],
+    ESCROW_PROGRAM_ID
+  );
   let initEscrowIx = new TransactionInstruction({
     keys: [
       { pubkey: walletPublicKey, isSigner: true, isWritable: true },
@@ -125,9 +160,10 @@ async function init() {
         isWritable: false,
       },
       { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+      { pubkey: configKey, isSigner: false, isWritable: false },
     ],
     programId: ESCROW_PROGRAM_ID,
-    data: payload,
+    data: payload.subarray(0, payloadLen),
   });
   let tx = new Transaction().add(initEscrowIx);
 
@@ -180,11 +216,14 @@ async function exchange() {
   let mintAReceiver = getAssociatedTokenAddressSync(mintA, taker.publicKey);
   let mintBRecieveAta = getAssociatedTokenAddressSync(mintB, initializerPubkey);
 
-  let payload = Buffer.alloc(EXCHANGE_LAYOUT.span);
-  EXCHANGE_LAYOUT.encode(
+  let payload = Buffer.alloc(1000);
+  let payloadLen = EXCHANGE_LAYOUT.encode(
     {
       instruction: new BN(1),
       amountToTrade: escrowState.expectedAmount,
+      fillAmount: escrowState.remainingAmount,
+      minAmountA: escrowState.remainingAmount,
+      proof: [],
     },
     payload
   );
@@ -194,6 +233,10 @@ async function exchange() {
     escrowKey,
     true
   );
+  let [configKey] = PublicKey.findProgramAddressSync(
+    [Buffer.from("config")],
+    ESCROW_PROGRAM_ID
+  );
   let ix = new TransactionInstruction({
     keys: [
       { pubkey: taker.publicKey, isSigner: true, isWritable: true },
@@ -212,14 +255,19 @@ async function exchange() {
       { pubkey: mintA, isSigner: false, isWritable: false },
       { pubkey: mintB, isSigner: false, isWritable: false },
       { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       {
         pubkey: ASSOCIATED_TOKEN_PROGRAM_ID,
         isSigner: false,
         isWritable: false,
       },
       { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+      { pubkey: configKey, isSigner: false, isWritable: false },
+      // no protocol fee is taken while the config is not initialized, nor are the fee accounts checked
+      { pubkey: configKey, isSigner: false, isWritable: true },
+      { pubkey: configKey, isSigner: false, isWritable: false },
     ],
-    data: payload,
+    data: payload.subarray(0, payloadLen),
     programId: ESCROW_PROGRAM_ID,
   });
 